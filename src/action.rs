@@ -1,7 +1,37 @@
+use crate::clipboard;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Action {
     Print(char),
     Execute(u8),
-    DispatchCSI(Vec<i64>, Vec<u8>, bool, char),
+    // Each parameter carries its `:`-separated subparameters.
+    DispatchCSI(Vec<Vec<i64>>, Vec<u8>, bool, char),
+    DispatchESC(Vec<u8>, bool, u8),
+    DispatchOSC(Vec<Vec<u8>>, bool),
+    DispatchAPC(Vec<u8>),
+    // A DCS sequence and its payload.
+    DispatchDCS(Vec<Vec<i64>>, Vec<u8>, bool, char, Vec<u8>),
+    Resize(usize, usize),
+    Redraw(),
+    // The cell size in physical pixels and the DPI factor.
+    SetCellSize(f64, f64, f64),
+    // The mouse pointer moved to a position in logical pixels.
+    MouseMoved(f64, f64),
+    MouseLeft(),
+    // The pending responses have been written to the pty.
+    ResponsesSent(),
+    ClipboardRequestsHandled(),
+    // The user answered whether an OSC 52 request is allowed.
+    ClipboardRequestAnswered(clipboard::Request, bool),
+    // The bell has been rung and the notifications shown.
+    NotificationsHandled(),
+    ScrollLines(i64),
+    ScrollPages(i64),
+    ScrollToBottom(),
+    // Scrolls to the previous (negative) or next (positive) OSC 133 prompt.
+    JumpToPrompt(i64),
+    SelectLastOutput(),
+    // Opens another window in the current working directory.
+    NewWindow(),
     Close(),
 }
@@ -0,0 +1,65 @@
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    // DEC Special Graphics, used for line drawing.
+    DecSpecialGraphics,
+    Uk,
+}
+
+impl Charset {
+    // Maps the final byte of a designation sequence (`ESC ( F`) to a charset.
+    pub fn from_designator(byte: u8) -> Option<Self> {
+        match byte {
+            b'B' => Some(Charset::Ascii),
+            b'0' => Some(Charset::DecSpecialGraphics),
+            b'A' => Some(Charset::Uk),
+            _ => None,
+        }
+    }
+
+    pub fn translate(self, c: char) -> char {
+        match self {
+            Charset::Ascii => c,
+            Charset::Uk => match c {
+                '#' => '£',
+                c => c,
+            },
+            Charset::DecSpecialGraphics => match c {
+                '_' => '\u{a0}',
+                '`' => '◆',
+                'a' => '▒',
+                'b' => '␉',
+                'c' => '␌',
+                'd' => '␍',
+                'e' => '␊',
+                'f' => '°',
+                'g' => '±',
+                'h' => '␤',
+                'i' => '␋',
+                'j' => '┘',
+                'k' => '┐',
+                'l' => '┌',
+                'm' => '└',
+                'n' => '┼',
+                'o' => '⎺',
+                'p' => '⎻',
+                'q' => '─',
+                'r' => '⎼',
+                's' => '⎽',
+                't' => '├',
+                'u' => '┤',
+                'v' => '┴',
+                'w' => '┬',
+                'x' => '│',
+                'y' => '≤',
+                'z' => '≥',
+                '{' => 'π',
+                '|' => '≠',
+                '}' => '£',
+                '~' => '·',
+                c => c,
+            },
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selection {
+    Clipboard,
+    Primary,
+}
+
+impl Selection {
+    // Parses the `Pc` parameter of OSC 52. An empty one means `s0`, and `s`
+    // is the primary selection as in xterm's default configuration. Cut
+    // buffers are not supported.
+    pub fn parse_all(pc: &str) -> Vec<Self> {
+        let pc = if pc.is_empty() { "s0" } else { pc };
+        let mut selections = vec![];
+        for c in pc.chars() {
+            let selection = match c {
+                'c' => Selection::Clipboard,
+                'p' | 's' => Selection::Primary,
+                _ => continue,
+            };
+            if !selections.contains(&selection) {
+                selections.push(selection);
+            }
+        }
+        selections
+    }
+
+    fn code(self) -> char {
+        match self {
+            Selection::Clipboard => 'c',
+            Selection::Primary => 'p',
+        }
+    }
+}
+
+// An OSC 52 request from the application.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Set(Vec<Selection>, String),
+    // Reply with the contents, terminated with BEL if `true` and ST otherwise.
+    Query(Selection, bool),
+}
+
+pub trait Provider {
+    fn get(&mut self, selection: Selection) -> Option<String>;
+    fn set(&mut self, selection: Selection, text: &str);
+}
+
+// Keeps the selections in memory, for platforms without a system provider
+// and for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryProvider {
+    pub clipboard: String,
+    pub primary: String,
+}
+
+impl Provider for MemoryProvider {
+    fn get(&mut self, selection: Selection) -> Option<String> {
+        match selection {
+            Selection::Clipboard => Some(self.clipboard.clone()),
+            Selection::Primary => Some(self.primary.clone()),
+        }
+    }
+
+    fn set(&mut self, selection: Selection, text: &str) {
+        match selection {
+            Selection::Clipboard => self.clipboard = text.to_string(),
+            Selection::Primary => self.primary = text.to_string(),
+        }
+    }
+}
+
+// Accesses the X11 selections through `xclip`.
+pub struct XclipProvider;
+
+impl XclipProvider {
+    fn selection_name(selection: Selection) -> &'static str {
+        match selection {
+            Selection::Clipboard => "clipboard",
+            Selection::Primary => "primary",
+        }
+    }
+}
+
+impl Provider for XclipProvider {
+    fn get(&mut self, selection: Selection) -> Option<String> {
+        let output = Command::new("xclip")
+            .args(["-selection", Self::selection_name(selection), "-o"])
+            .output()
+            .ok()?;
+        String::from_utf8(output.stdout).ok()
+    }
+
+    fn set(&mut self, selection: Selection, text: &str) {
+        let child = Command::new("xclip")
+            .args(["-selection", Self::selection_name(selection), "-i"])
+            .stdin(Stdio::piped())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(text.as_bytes());
+                }
+                let _ = child.wait();
+            }
+            Err(e) => eprintln!("failed to run xclip: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    Allow,
+    Deny,
+    // Ask the user each time.
+    Ask,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "allow" => Some(Policy::Allow),
+            "deny" => Some(Policy::Deny),
+            "ask" => Some(Policy::Ask),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub write: Policy,
+    pub read: Policy,
+}
+
+impl Default for Config {
+    // Applications may copy, but not read what the user copied elsewhere.
+    fn default() -> Self {
+        Self {
+            write: Policy::Allow,
+            read: Policy::Deny,
+        }
+    }
+}
+
+pub struct Clipboard {
+    provider: Box<dyn Provider>,
+    config: Config,
+}
+
+impl Clipboard {
+    pub fn new(provider: Box<dyn Provider>, config: Config) -> Self {
+        Self { provider, config }
+    }
+
+    // Whether a request may be carried out. Asking the user is left to the
+    // caller, which carries out the request once it is confirmed.
+    pub fn policy(&self, request: &Request) -> Policy {
+        match request {
+            Request::Set(..) => self.config.write,
+            Request::Query(..) => self.config.read,
+        }
+    }
+
+    // Carries out an allowed request, returning the reply to send to the
+    // application, if any.
+    pub fn carry_out(&mut self, request: &Request) -> Option<String> {
+        match request {
+            Request::Set(selections, text) => {
+                for &selection in selections {
+                    self.provider.set(selection, text);
+                }
+                None
+            }
+            Request::Query(selection, bell_terminated) => {
+                let text = self.provider.get(*selection).unwrap_or_default();
+                let terminator = if *bell_terminated { "\x07" } else { "\x1b\\" };
+                Some(format!(
+                    "\x1b]52;{};{}{}",
+                    selection.code(),
+                    base64::encode(text),
+                    terminator
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clipboard(write: Policy, read: Policy) -> Clipboard {
+        Clipboard::new(Box::new(MemoryProvider::default()), Config { write, read })
+    }
+
+    fn set(selection: Selection, text: &str) -> Request {
+        Request::Set(vec![selection], text.to_string())
+    }
+
+    #[test]
+    fn queries_are_denied_by_default() {
+        let clipboard = clipboard(Config::default().write, Config::default().read);
+        assert_eq!(
+            clipboard.policy(&set(Selection::Clipboard, "hello")),
+            Policy::Allow
+        );
+        assert_eq!(
+            clipboard.policy(&Request::Query(Selection::Clipboard, true)),
+            Policy::Deny
+        );
+    }
+
+    #[test]
+    fn queries_reply_in_base64() {
+        let mut clipboard = clipboard(Policy::Allow, Policy::Allow);
+        clipboard.carry_out(&set(Selection::Primary, "hi"));
+        assert_eq!(
+            clipboard.carry_out(&Request::Query(Selection::Primary, false)),
+            Some("\x1b]52;p;aGk=\x1b\\".to_string())
+        );
+        assert_eq!(
+            clipboard.carry_out(&Request::Query(Selection::Clipboard, true)),
+            Some("\x1b]52;c;\x07".to_string())
+        );
+    }
+
+    #[test]
+    fn ask_applies_to_each_kind_of_request() {
+        let clipboard = clipboard(Policy::Ask, Policy::Deny);
+        assert_eq!(
+            clipboard.policy(&set(Selection::Clipboard, "secret")),
+            Policy::Ask
+        );
+        assert_eq!(
+            clipboard.policy(&Request::Query(Selection::Clipboard, true)),
+            Policy::Deny
+        );
+    }
+
+    #[test]
+    fn selections_are_parsed_from_pc() {
+        assert_eq!(Selection::parse_all(""), vec![Selection::Primary]);
+        assert_eq!(
+            Selection::parse_all("cps7"),
+            vec![Selection::Clipboard, Selection::Primary]
+        );
+    }
+}
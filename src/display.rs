@@ -1,47 +1,79 @@
-use crate::state::State;
+use crate::graphics::BELOW_BACKGROUND;
+use crate::mode::Mode;
+use crate::palette::{Palette, Rgb};
+use crate::state::{Cell, Color, State};
 
-use glium;
 use glium::glutin::{ContextBuilder, ContextTrait, EventsLoop, WindowBuilder};
-use glium::{implement_vertex, program, uniform, Surface};
+use glium::{program, uniform, Surface};
 
 use harfbuzz_rs::Owned;
 use harfbuzz_rs::{shape, Font as HBFont, UnicodeBuffer};
 use rusttype::gpu_cache::Cache;
-use rusttype::{point, vector, Font, GlyphId, PositionedGlyph, Rect, Scale};
+use rusttype::{point, Font, GlyphId, PositionedGlyph, Scale};
+
+use self::vertex::{ImageVertex, RectVertex, Vertex};
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 pub use glium::glutin::{Event, WindowEvent};
 
 pub struct Display<'a> {
     display: glium::Display,
     program: glium::Program,
+    rect_program: glium::Program,
+    image_program: glium::Program,
+    // Textures of the images on the grid, by placement id.
+    textures: HashMap<u64, glium::texture::Texture2d>,
     font: Font<'a>,
     hb_font: Owned<HBFont<'a>>,
     cache: Cache<'a>,
     cache_tex: glium::texture::Texture2d,
+    title: String,
+    // Kept up to date by the event loop.
+    focused: Arc<AtomicBool>,
+    urgent: bool,
+    // Blinking text is shown and hidden in turns from this time on.
+    blink_start: Instant,
+    // Whether the last frame had blinking text in it.
+    blinking: bool,
 }
 
+const DEFAULT_TITLE: &str = "GreenTTY";
+
+const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+// How far italic glyphs lean, in pixels across per pixel up.
+const ITALIC_SLANT: f32 = 0.2;
+
 pub trait Handler {
     fn on_window_event(&mut self, event: &WindowEvent);
 }
 
 impl<'a> Display<'a> {
-    pub fn open<H: Handler + Send + 'static>(mut handler: H) -> Result<Self, Box<Error>> {
+    pub fn open<H: Handler + Send + 'static>(mut handler: H) -> Result<Self, Box<dyn Error>> {
         let (tx, rx) = channel();
+        let focused = Arc::new(AtomicBool::new(true));
+        let window_focused = focused.clone();
         thread::spawn(move || {
             let window = WindowBuilder::new()
                 .with_dimensions((1024, 512).into())
-                .with_title("GreenTTY");
+                .with_title(DEFAULT_TITLE);
             let context = ContextBuilder::new().with_vsync(true);
             let mut events_loop = EventsLoop::new();
             let context = context.build_windowed(window, &events_loop).unwrap();
             tx.send(context).unwrap();
             events_loop.run_forever(|e| {
                 if let Event::WindowEvent { event, .. } = e {
+                    if let WindowEvent::Focused(focused) = event {
+                        window_focused.store(focused, Ordering::Relaxed);
+                    }
                     handler.on_window_event(&event);
                 }
                 glium::glutin::ControlFlow::Continue
@@ -96,6 +128,53 @@ impl<'a> Display<'a> {
             "
         })?;
 
+        let rect_program = program!(
+        &display,
+        140 => {
+            vertex: "
+                #version 140
+                in vec2 position;
+                in vec4 colour;
+                out vec4 v_colour;
+                void main() {
+                    gl_Position = vec4(position, 0.0, 1.0);
+                    v_colour = colour;
+                }
+            ",
+            fragment: "
+                #version 140
+                in vec4 v_colour;
+                out vec4 f_colour;
+                void main() {
+                    f_colour = v_colour;
+                }
+            "
+        })?;
+
+        let image_program = program!(
+        &display,
+        140 => {
+            vertex: "
+                #version 140
+                in vec2 position;
+                in vec2 tex_coords;
+                out vec2 v_tex_coords;
+                void main() {
+                    gl_Position = vec4(position, 0.0, 1.0);
+                    v_tex_coords = tex_coords;
+                }
+            ",
+            fragment: "
+                #version 140
+                uniform sampler2D tex;
+                in vec2 v_tex_coords;
+                out vec4 f_colour;
+                void main() {
+                    f_colour = texture(tex, v_tex_coords);
+                }
+            "
+        })?;
+
         let cache_tex = glium::texture::Texture2d::with_format(
             &display,
             glium::texture::RawImage2d {
@@ -111,38 +190,109 @@ impl<'a> Display<'a> {
         Ok(Display {
             display,
             program,
+            rect_program,
+            image_program,
+            textures: HashMap::new(),
             font,
             hb_font,
             cache,
             cache_tex,
+            title: String::new(),
+            focused,
+            urgent: false,
+            blink_start: Instant::now(),
+            blinking: false,
         })
     }
 
-    pub fn render(&mut self, state: &State) -> Result<(), Box<Error>> {
-        let dpi_factor = self.display.gl_window().get_hidpi_factor();
-        let (width, _): (u32, _) = self
-            .display
-            .gl_window()
-            .get_inner_size()
-            .ok_or("get_inner_size")?
-            .to_physical(dpi_factor)
-            .into();
-        let dpi_factor = dpi_factor as f32;
-
-        let lines = state
-            .lines
-            .iter()
-            .map(|line| line.iter().collect::<String>())
-            .collect::<Vec<String>>();
-
-        let glyphs = layout_paragraph(
-            &self.font,
-            &self.hb_font,
-            Scale::uniform(24.0 * dpi_factor),
-            width,
-            &lines,
-        );
-        for glyph in &glyphs {
+    pub fn is_focused(&self) -> bool {
+        self.focused.load(Ordering::Relaxed)
+    }
+
+    // When the screen next changes without any new output, e.g. because
+    // blinking text turns on or off.
+    pub fn next_redraw(&self) -> Option<Instant> {
+        if !self.blinking {
+            return None;
+        }
+        let phases = self.blink_start.elapsed().as_millis() / BLINK_INTERVAL.as_millis() + 1;
+        Some(self.blink_start + BLINK_INTERVAL * phases as u32)
+    }
+
+    // Asks the window manager to draw attention to the window until it is
+    // focused.
+    pub fn request_attention(&mut self) {
+        self.urgent = true;
+        self.set_urgent(true);
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn set_urgent(&self, urgent: bool) {
+        use glium::glutin::os::unix::WindowExt;
+        self.display.gl_window().set_urgent(urgent);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn set_urgent(&self, urgent: bool) {
+        use glium::glutin::os::macos::WindowExt;
+        if urgent {
+            self.display.gl_window().request_user_attention(false);
+        }
+    }
+
+    #[cfg(windows)]
+    fn set_urgent(&self, _urgent: bool) {}
+
+    fn metrics(&self) -> Metrics {
+        let dpi_factor = self.display.gl_window().get_hidpi_factor() as f32;
+        Metrics::new(&self.font, Scale::uniform(24.0 * dpi_factor))
+    }
+
+    // The size of a cell in physical pixels.
+    pub fn cell_size(&self) -> (f64, f64) {
+        let metrics = self.metrics();
+        (
+            f64::from(metrics.cell_width),
+            f64::from(metrics.cell_height),
+        )
+    }
+
+    pub fn dpi_factor(&self) -> f64 {
+        self.display.gl_window().get_hidpi_factor()
+    }
+
+    // The number of columns and rows that fit in the window.
+    pub fn grid_size(&self) -> (usize, usize) {
+        let metrics = self.metrics();
+        let (width, height) = self.display.get_framebuffer_dimensions();
+        let cols = (width as f32 / metrics.cell_width) as usize;
+        let rows = (height as f32 / metrics.cell_height) as usize;
+        (cols.max(1), rows.max(1))
+    }
+
+    pub fn render(&mut self, state: &State) -> Result<(), Box<dyn Error>> {
+        if state.title != self.title {
+            self.title = state.title.clone();
+            let title = if self.title.is_empty() {
+                DEFAULT_TITLE
+            } else {
+                &self.title
+            };
+            self.display.gl_window().set_title(title);
+        }
+
+        if self.urgent && self.is_focused() {
+            self.urgent = false;
+            self.set_urgent(false);
+        }
+
+        let metrics = self.metrics();
+        let defaults = Defaults::new(&state.palette, state.modes.get(Mode::ReverseVideo));
+
+        let lines: Vec<&[Cell]> = state.visible_lines().map(|line| &line[..]).collect();
+        let glyphs = layout_grid(&self.font, &self.hb_font, &metrics, &lines);
+
+        for (glyph, _) in &glyphs {
             self.cache.queue_glyph(0, glyph.clone());
         }
         let cache_tex = &self.cache_tex;
@@ -165,138 +315,433 @@ impl<'a> Display<'a> {
         let uniforms = uniform! {
             tex: self.cache_tex.sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
         };
-        let vertex_buffer = {
-            #[derive(Copy, Clone)]
-            struct Vertex {
-                position: [f32; 2],
-                tex_coords: [f32; 2],
-                colour: [f32; 4],
-            }
 
-            implement_vertex!(Vertex, position, tex_coords, colour);
-            let colour = [1.0, 1.0, 1.0, 1.0];
-            let (screen_width, screen_height) = {
-                let (w, h) = self.display.get_framebuffer_dimensions();
-                (w as f32, h as f32)
+        let screen = {
+            let (w, h) = self.display.get_framebuffer_dimensions();
+            (w as f32, h as f32)
+        };
+
+        let hovered_link = state.hovered_hyperlink();
+        self.blinking = lines
+            .iter()
+            .any(|line| line.iter().any(|cell| cell.attrs.blink));
+        let blink_on =
+            (self.blink_start.elapsed().as_millis() / BLINK_INTERVAL.as_millis()).is_multiple_of(2);
+        let mut rects = Vec::new();
+        for (y, line) in lines.iter().enumerate() {
+            for (x, cell) in line.iter().enumerate() {
+                let (fg, bg) = cell_colours(cell, defaults);
+                let left = x as f32 * metrics.cell_width;
+                let top = y as f32 * metrics.cell_height;
+                let right = left + metrics.cell_width;
+                if cell.bg != Color::Default || cell.attrs.inverse {
+                    push_rect(
+                        &mut rects,
+                        screen,
+                        (left, top, right, top + metrics.cell_height),
+                        bg,
+                    );
+                }
+                if cell.attrs.hidden || (cell.attrs.blink && !blink_on) {
+                    continue;
+                }
+                let baseline = top + metrics.ascent;
+                let thickness = (metrics.cell_height / 16.0).max(1.0);
+                if cell.attrs.underline
+                    || (cell.hyperlink.is_some() && cell.hyperlink == hovered_link)
+                {
+                    let y = baseline + thickness;
+                    push_rect(&mut rects, screen, (left, y, right, y + thickness), fg);
+                }
+                if cell.attrs.strikethrough {
+                    let y = baseline - metrics.ascent / 3.0;
+                    push_rect(&mut rects, screen, (left, y, right, y + thickness), fg);
+                }
+            }
+        }
+        let width = state.cols as f32 * metrics.cell_width;
+        if let Some(rows) = state.visible_selection() {
+            let [r, g, b, _] = defaults.fg;
+            for y in rows {
+                let top = y as f32 * metrics.cell_height;
+                let bottom = top + metrics.cell_height;
+                push_rect(
+                    &mut rects,
+                    screen,
+                    (0.0, top, width, bottom),
+                    [r, g, b, 0.25],
+                );
+            }
+        }
+        // Gutter marks for finished commands (OSC 133), green on success.
+        for (y, line) in state.visible_lines().enumerate() {
+            let colour = match line.marks.exit_status {
+                Some(0) => rgba(state.palette.colors[2]),
+                Some(_) => rgba(state.palette.colors[1]),
+                None => continue,
+            };
+            let top = y as f32 * metrics.cell_height;
+            let bottom = top + metrics.cell_height;
+            let right = (metrics.cell_width / 4.0).max(1.0);
+            push_rect(&mut rects, screen, (0.0, top, right, bottom), colour);
+        }
+        if state.modes.get(Mode::CursorVisible) && state.viewport_offset == 0 {
+            let left = state.cursor.x as f32 * metrics.cell_width;
+            let top = state.cursor.y as f32 * metrics.cell_height;
+            let right = left + metrics.cell_width;
+            let bottom = top + metrics.cell_height;
+            let thickness = (metrics.cell_height / 12.0).max(1.0);
+            let [r, g, b, _] = defaults.cursor;
+            let (bounds, alpha) = match state.cursor_style {
+                3 | 4 => ((left, bottom - thickness, right, bottom), 1.0),
+                5 | 6 => ((left, top, left + thickness, bottom), 1.0),
+                _ => ((left, top, right, bottom), 0.5),
             };
-            let origin = point(0.0, 0.0);
-            let vertices: Vec<Vertex> = glyphs
-                .iter()
-                .flat_map(|g| {
-                    if let Ok(Some((uv_rect, screen_rect))) = self.cache.rect_for(0, g) {
-                        let gl_rect = Rect {
-                            min: origin
-                                + (vector(
-                                    screen_rect.min.x as f32 / screen_width - 0.5,
-                                    1.0 - screen_rect.min.y as f32 / screen_height - 0.5,
-                                )) * 2.0,
-                            max: origin
-                                + (vector(
-                                    screen_rect.max.x as f32 / screen_width - 0.5,
-                                    1.0 - screen_rect.max.y as f32 / screen_height - 0.5,
-                                )) * 2.0,
-                        };
-                        arrayvec::ArrayVec::<[Vertex; 6]>::from([
-                            Vertex {
-                                position: [gl_rect.min.x, gl_rect.max.y],
-                                tex_coords: [uv_rect.min.x, uv_rect.max.y],
-
-                                colour,
-                            },
-                            Vertex {
-                                position: [gl_rect.min.x, gl_rect.min.y],
-                                tex_coords: [uv_rect.min.x, uv_rect.min.y],
-                                colour,
-                            },
-                            Vertex {
-                                position: [gl_rect.max.x, gl_rect.min.y],
-                                tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                                colour,
-                            },
-                            Vertex {
-                                position: [gl_rect.max.x, gl_rect.min.y],
-                                tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                                colour,
-                            },
-                            Vertex {
-                                position: [gl_rect.max.x, gl_rect.max.y],
-                                tex_coords: [uv_rect.max.x, uv_rect.max.y],
-                                colour,
-                            },
-                            Vertex {
-                                position: [gl_rect.min.x, gl_rect.max.y],
-                                tex_coords: [uv_rect.min.x, uv_rect.max.y],
-                                colour,
-                            },
-                        ])
+            push_rect(&mut rects, screen, bounds, [r, g, b, alpha]);
+        }
+        if state.flashing() {
+            let [r, g, b, _] = defaults.fg;
+            push_rect(
+                &mut rects,
+                screen,
+                (0.0, 0.0, screen.0, screen.1),
+                [r, g, b, 0.3],
+            );
+        }
+        let rect_buffer = glium::VertexBuffer::new(&self.display, &rects)?;
+
+        let mut vertices = Vec::new();
+        for (glyph, cell) in &glyphs {
+            if cell.attrs.blink && !blink_on {
+                continue;
+            }
+            let colour = cell_colours(cell, defaults).0;
+            if let Ok(Some((uv_rect, screen_rect))) = self.cache.rect_for(0, glyph) {
+                let (left, right) = (screen_rect.min.x as f32, screen_rect.max.x as f32);
+                let (top, bottom) = (screen_rect.min.y as f32, screen_rect.max.y as f32);
+                // Italic is drawn by slanting the glyph around the baseline.
+                let baseline = glyph.position().y;
+                let slant = |y: f32| {
+                    if cell.attrs.italic {
+                        (baseline - y) * ITALIC_SLANT
                     } else {
-                        arrayvec::ArrayVec::new()
+                        0.0
                     }
-                })
-                .collect();
+                };
+                let (top_left, gl_top) = to_gl(screen, left + slant(top), top);
+                let (bottom_left, gl_bottom) = to_gl(screen, left + slant(bottom), bottom);
+                let (top_right, _) = to_gl(screen, right + slant(top), top);
+                let (bottom_right, _) = to_gl(screen, right + slant(bottom), bottom);
+                let corners = [
+                    ([top_left, gl_top], [uv_rect.min.x, uv_rect.min.y]),
+                    ([bottom_left, gl_bottom], [uv_rect.min.x, uv_rect.max.y]),
+                    ([bottom_right, gl_bottom], [uv_rect.max.x, uv_rect.max.y]),
+                    ([bottom_right, gl_bottom], [uv_rect.max.x, uv_rect.max.y]),
+                    ([top_right, gl_top], [uv_rect.max.x, uv_rect.min.y]),
+                    ([top_left, gl_top], [uv_rect.min.x, uv_rect.min.y]),
+                ];
+                for &(position, tex_coords) in &corners {
+                    vertices.push(Vertex {
+                        position,
+                        tex_coords,
+                        colour,
+                    });
+                }
+            }
+        }
+        let vertex_buffer = glium::VertexBuffer::new(&self.display, &vertices)?;
 
-            glium::VertexBuffer::new(&self.display, &vertices)?
+        let draw_parameters = glium::DrawParameters {
+            blend: glium::Blend::alpha_blending(),
+            ..Default::default()
         };
+        // Images are drawn in order of z-index, under the backgrounds, between
+        // the backgrounds and the text, or over the text.
+        self.textures
+            .retain(|id, _| state.images.iter().any(|image| image.id == *id));
+        let mut images = Vec::new();
+        let top = state.viewport_top() as f32;
+        for image in state.visible_images() {
+            if !self.textures.contains_key(&image.id) {
+                let bitmap = &image.bitmap;
+                let raw = glium::texture::RawImage2d::from_raw_rgba(
+                    bitmap.pixels.clone(),
+                    (bitmap.width, bitmap.height),
+                );
+                let texture = glium::texture::Texture2d::new(&self.display, raw)?;
+                self.textures.insert(image.id, texture);
+            }
+            let (x, y) = image.offset;
+            let left = image.col as f32 * metrics.cell_width + x as f32;
+            let top = (image.row as f32 - top) * metrics.cell_height + y as f32;
+            let (min_x, max_y) = to_gl(screen, left, top);
+            let (max_x, min_y) =
+                to_gl(screen, left + image.width as f32, top + image.height as f32);
+            // The bitmap rows go from the top, so v grows downwards.
+            let vertices = [
+                ImageVertex {
+                    position: [min_x, max_y],
+                    tex_coords: [0.0, 0.0],
+                },
+                ImageVertex {
+                    position: [min_x, min_y],
+                    tex_coords: [0.0, 1.0],
+                },
+                ImageVertex {
+                    position: [max_x, min_y],
+                    tex_coords: [1.0, 1.0],
+                },
+                ImageVertex {
+                    position: [max_x, min_y],
+                    tex_coords: [1.0, 1.0],
+                },
+                ImageVertex {
+                    position: [max_x, max_y],
+                    tex_coords: [1.0, 0.0],
+                },
+                ImageVertex {
+                    position: [min_x, max_y],
+                    tex_coords: [0.0, 0.0],
+                },
+            ];
+            let buffer = glium::VertexBuffer::new(&self.display, &vertices)?;
+            images.push((image_layer(image.z_index), image.id, buffer));
+        }
+        images.sort_by_key(|&(layer, _, _)| layer);
 
         let mut target = self.display.draw();
-        target.clear_color(0.0, 0.0, 0.0, 0.0);
+        let [r, g, b, a] = defaults.bg;
+        target.clear_color(r, g, b, a);
+        self.draw_images(&mut target, &images, 0, &draw_parameters)?;
+        target.draw(
+            &rect_buffer,
+            glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+            &self.rect_program,
+            &glium::uniforms::EmptyUniforms,
+            &draw_parameters,
+        )?;
+        self.draw_images(&mut target, &images, 1, &draw_parameters)?;
         target.draw(
             &vertex_buffer,
             glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
             &self.program,
             &uniforms,
-            &glium::DrawParameters {
-                blend: glium::Blend::alpha_blending(),
-                ..Default::default()
-            },
+            &draw_parameters,
         )?;
+        self.draw_images(&mut target, &images, 2, &draw_parameters)?;
         target.finish()?;
 
         Ok(())
     }
+
+    fn draw_images(
+        &self,
+        target: &mut glium::Frame,
+        images: &[(i32, u64, glium::VertexBuffer<ImageVertex>)],
+        layer: i32,
+        draw_parameters: &glium::DrawParameters,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, id, buffer) in images.iter().filter(|image| image.0 == layer) {
+            let uniforms = uniform! {
+                tex: self.textures[id].sampled()
+            };
+            target.draw(
+                buffer,
+                glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                &self.image_program,
+                &uniforms,
+                draw_parameters,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Images go under the cell backgrounds (0), under the text (1) or over it (2).
+fn image_layer(z_index: i32) -> i32 {
+    if z_index < BELOW_BACKGROUND {
+        0
+    } else if z_index < 0 {
+        1
+    } else {
+        2
+    }
+}
+
+// implement_vertex! still expands to mem::uninitialized.
+#[allow(deprecated)]
+mod vertex {
+    use glium::implement_vertex;
+
+    #[derive(Copy, Clone)]
+    pub struct Vertex {
+        pub position: [f32; 2],
+        pub tex_coords: [f32; 2],
+        pub colour: [f32; 4],
+    }
+    implement_vertex!(Vertex, position, tex_coords, colour);
+
+    #[derive(Copy, Clone)]
+    pub struct RectVertex {
+        pub position: [f32; 2],
+        pub colour: [f32; 4],
+    }
+    implement_vertex!(RectVertex, position, colour);
+
+    #[derive(Copy, Clone)]
+    pub struct ImageVertex {
+        pub position: [f32; 2],
+        pub tex_coords: [f32; 2],
+    }
+    implement_vertex!(ImageVertex, position, tex_coords);
+}
+
+// The colours that cells are resolved against.
+#[derive(Clone, Copy)]
+struct Defaults<'a> {
+    fg: [f32; 4],
+    bg: [f32; 4],
+    cursor: [f32; 4],
+    palette: &'a Palette,
+}
+
+impl<'a> Defaults<'a> {
+    // DECSCNM swaps the default colours of the whole screen. The background
+    // is otherwise left transparent.
+    fn new(palette: &'a Palette, reverse: bool) -> Self {
+        let mut fg = rgba(palette.foreground);
+        let mut bg = rgba(palette.background);
+        if reverse {
+            std::mem::swap(&mut fg, &mut bg);
+        } else {
+            bg[3] = 0.0;
+        }
+        let cursor = palette.cursor.map(rgba).unwrap_or(fg);
+        Self {
+            fg,
+            bg,
+            cursor,
+            palette,
+        }
+    }
 }
 
-fn layout_paragraph<'a, 'b>(
-    font: &'b Font<'a>,
-    hb_font: &'b HBFont,
+struct Metrics {
     scale: Scale,
-    width: u32,
-    lines: &'b Vec<String>,
-) -> Vec<PositionedGlyph<'a>> {
-    // use unicode_normalization::UnicodeNormalization;
-    let mut result = Vec::new();
-    let v_metrics = font.v_metrics(scale);
-    let advance_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
-    let mut caret = point(0.0, v_metrics.ascent);
-    let mut last_glyph_id = None;
+    ascent: f32,
+    cell_width: f32,
+    cell_height: f32,
+}
+
+impl Metrics {
+    fn new(font: &Font, scale: Scale) -> Self {
+        let v_metrics = font.v_metrics(scale);
+        let cell_width = font.glyph('M').scaled(scale).h_metrics().advance_width;
+        Self {
+            scale,
+            ascent: v_metrics.ascent,
+            cell_width,
+            cell_height: v_metrics.ascent - v_metrics.descent + v_metrics.line_gap,
+        }
+    }
+}
 
-    for line in &lines[..] {
-        let buffer = UnicodeBuffer::new().add_str(line);
-        let output = shape(&hb_font, buffer, &[]);
+fn to_gl((width, height): (f32, f32), x: f32, y: f32) -> (f32, f32) {
+    (x / width * 2.0 - 1.0, 1.0 - y / height * 2.0)
+}
 
-        let positions = output.get_glyph_positions();
-        let infos = output.get_glyph_infos();
+fn push_rect(
+    rects: &mut Vec<RectVertex>,
+    screen: (f32, f32),
+    (left, top, right, bottom): (f32, f32, f32, f32),
+    colour: [f32; 4],
+) {
+    let (min_x, max_y) = to_gl(screen, left, top);
+    let (max_x, min_y) = to_gl(screen, right, bottom);
+    for &position in &[
+        [min_x, max_y],
+        [min_x, min_y],
+        [max_x, min_y],
+        [max_x, min_y],
+        [max_x, max_y],
+        [min_x, max_y],
+    ] {
+        rects.push(RectVertex { position, colour });
+    }
+}
 
-        for (_position, info) in positions.iter().zip(infos) {
-            let base_glyph = font.glyph(GlyphId(info.codepoint));
+// Resolves the foreground and background colour of a cell, applying bold
+// brightening, dim and inverse.
+fn cell_colours(cell: &Cell, defaults: Defaults<'_>) -> ([f32; 4], [f32; 4]) {
+    let fg = match cell.fg {
+        Color::Indexed(n) if n < 8 && cell.attrs.bold => Color::Indexed(n + 8),
+        fg => fg,
+    };
+    let mut fg = colour(fg, defaults.fg, defaults.palette);
+    let mut bg = colour(cell.bg, defaults.bg, defaults.palette);
+    if cell.attrs.inverse {
+        if cell.bg == Color::Default {
+            bg[3] = 1.0;
+        }
+        std::mem::swap(&mut fg, &mut bg);
+    }
+    if cell.attrs.dim {
+        fg[3] *= 0.5;
+    }
+    (fg, bg)
+}
 
-            if let Some(id) = last_glyph_id.take() {
-                caret.x += font.pair_kerning(scale, id, base_glyph.id());
-            }
-            last_glyph_id = Some(base_glyph.id());
-            let mut glyph = base_glyph.scaled(scale).positioned(caret);
-            if let Some(bb) = glyph.pixel_bounding_box() {
-                if bb.max.x > width as i32 {
-                    caret = point(0.0, caret.y + advance_height);
-                    glyph.set_position(caret);
-                    last_glyph_id = None;
-                }
-            }
-            caret.x += glyph.unpositioned().h_metrics().advance_width;
-            result.push(glyph);
+fn colour(color: Color, default: [f32; 4], palette: &Palette) -> [f32; 4] {
+    match color {
+        Color::Default => default,
+        Color::Indexed(n) => rgba(palette.colors[n as usize]),
+        Color::Rgb(r, g, b) => rgba((r, g, b)),
+    }
+}
+
+fn rgba((r, g, b): Rgb) -> [f32; 4] {
+    [
+        f32::from(r) / 255.0,
+        f32::from(g) / 255.0,
+        f32::from(b) / 255.0,
+        1.0,
+    ]
+}
+
+// Shapes each line with harfbuzz and places the glyphs on the cell grid,
+// paired with the cell their cluster starts in.
+fn layout_grid<'a>(
+    font: &Font<'a>,
+    hb_font: &HBFont,
+    metrics: &Metrics,
+    lines: &[&[Cell]],
+) -> Vec<(PositionedGlyph<'a>, Cell)> {
+    let mut result = Vec::new();
+
+    for (y, line) in lines.iter().enumerate() {
+        let mut text = String::new();
+        let mut columns = Vec::new();
+        for (x, cell) in line.iter().enumerate() {
+            let c = if cell.attrs.hidden { ' ' } else { cell.c };
+            columns.extend(std::iter::repeat_n(x, c.len_utf8()));
+            text.push(c);
         }
 
-        caret = point(0.0, caret.y + advance_height);
+        let buffer = UnicodeBuffer::new().add_str(&text);
+        let output = shape(hb_font, buffer, &[]);
+
+        for info in output.get_glyph_infos() {
+            let x = columns[info.cluster as usize];
+            let caret = point(
+                x as f32 * metrics.cell_width,
+                y as f32 * metrics.cell_height + metrics.ascent,
+            );
+            let glyph = font
+                .glyph(GlyphId(info.codepoint))
+                .scaled(metrics.scale)
+                .positioned(caret);
+            result.push((glyph, line[x]));
+        }
     }
 
     result
@@ -0,0 +1,211 @@
+pub mod iterm;
+pub mod kitty;
+pub mod sixel;
+
+use std::sync::Arc;
+
+// Images larger than this in either direction are rejected.
+pub const MAX_IMAGE_SIZE: u32 = 4096;
+
+// The most memory taken by the pixels of stored and placed images, as in
+// kitty's default storage quota. The oldest are dropped to make room.
+pub const MAX_IMAGE_BYTES: usize = 320 * 1024 * 1024;
+
+// Images may be larger than the screen, but only by this factor in either
+// direction, however many cells they ask for.
+pub const MAX_IMAGE_SCREENS: f64 = 4.0;
+
+// An RGBA image, with 4 bytes per pixel in rows from the top.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    // Copies the `width` by `height` rectangle at `(x, y)`, clipped to the
+    // bitmap.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+        let mut cropped = Self::new(width, height);
+        let row_len = width as usize * 4;
+        for row in 0..height as usize {
+            let from = ((y as usize + row) * self.width as usize + x as usize) * 4;
+            let to = row * row_len;
+            cropped.pixels[to..to + row_len].copy_from_slice(&self.pixels[from..from + row_len]);
+        }
+        cropped
+    }
+}
+
+// Decodes a PNG image of any colour type into RGBA.
+pub fn decode_png(data: &[u8]) -> Result<Bitmap, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let (width, height) = {
+        let info = reader.info();
+        (info.width, info.height)
+    };
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        return Err("image too large".to_string());
+    }
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    let data = &buffer[..frame.buffer_size()];
+    let pixels = match frame.color_type {
+        png::ColorType::Rgba => data.to_vec(),
+        png::ColorType::Rgb => data
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => data
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return Err("unexpanded palette".to_string()),
+    };
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+// Decodes a PNG, JPEG or GIF image, telling them apart by their first
+// bytes. Only the first frame of an animated GIF is kept.
+pub fn decode_image(data: &[u8]) -> Result<Bitmap, String> {
+    if data.starts_with(b"\x89PNG") {
+        decode_png(data)
+    } else if data.starts_with(b"\xff\xd8") {
+        decode_jpeg(data)
+    } else if data.starts_with(b"GIF8") {
+        decode_gif(data)
+    } else {
+        Err("unknown image format".to_string())
+    }
+}
+
+fn decode_jpeg(data: &[u8]) -> Result<Bitmap, String> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info().map_err(|e| e.to_string())?;
+    let info = decoder.info().ok_or_else(|| "missing header".to_string())?;
+    let (width, height) = (u32::from(info.width), u32::from(info.height));
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        return Err("image too large".to_string());
+    }
+    let data = decoder.decode().map_err(|e| e.to_string())?;
+    let pixels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => data.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        // Big-endian, so the first byte is the most significant.
+        jpeg_decoder::PixelFormat::L16 => data
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], 255])
+            .collect(),
+        jpeg_decoder::PixelFormat::RGB24 => data
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        jpeg_decoder::PixelFormat::CMYK32 => data
+            .chunks(4)
+            .flat_map(|p| {
+                let k = 255 - u32::from(p[3]);
+                let channel = |v: u8| ((255 - u32::from(v)) * k / 255) as u8;
+                [channel(p[0]), channel(p[1]), channel(p[2]), 255]
+            })
+            .collect(),
+    };
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn decode_gif(data: &[u8]) -> Result<Bitmap, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data).map_err(|e| e.to_string())?;
+    let (width, height) = (u32::from(decoder.width()), u32::from(decoder.height()));
+    if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+        return Err("image too large".to_string());
+    }
+    let frame = decoder
+        .read_next_frame()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "no frames".to_string())?;
+    // The frame may cover only part of the canvas, which is transparent
+    // elsewhere.
+    let mut bitmap = Bitmap::new(width, height);
+    let (left, top) = (usize::from(frame.left), usize::from(frame.top));
+    let frame_width = usize::from(frame.width);
+    for (y, row) in frame.buffer.chunks(frame_width.max(1) * 4).enumerate() {
+        let y = top + y;
+        if y >= height as usize || left >= width as usize {
+            break;
+        }
+        let len = row.len().min((width as usize - left) * 4);
+        let start = (y * width as usize + left) * 4;
+        bitmap.pixels[start..start + len].copy_from_slice(&row[..len]);
+    }
+    Ok(bitmap)
+}
+
+// An image shown on the grid with its top left corner at a cell. It moves
+// with the text around it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Placement {
+    // Unique for each placement, so that the renderer can cache textures.
+    pub id: u64,
+    pub bitmap: Arc<Bitmap>,
+    // Counted from the oldest line in the scrollback, like the selection.
+    pub row: usize,
+    pub col: usize,
+    // The offset from the top left corner of the cell, and the size on
+    // screen, in physical pixels.
+    pub offset: (f64, f64),
+    pub width: f64,
+    pub height: f64,
+    // Images with a negative z-index are drawn under the text, and those
+    // below `BELOW_BACKGROUND` under the cell backgrounds too.
+    pub z_index: i32,
+    // The kitty image and placement ids, or zero.
+    pub image_id: u32,
+    pub placement_id: u32,
+    // Whether it was placed on the alternate screen.
+    pub alternate: bool,
+}
+
+pub const BELOW_BACKGROUND: i32 = -1_073_741_824;
+
+impl Placement {
+    pub fn new(bitmap: Arc<Bitmap>) -> Self {
+        let (width, height) = (f64::from(bitmap.width), f64::from(bitmap.height));
+        Self {
+            id: 0,
+            bitmap,
+            row: 0,
+            col: 0,
+            offset: (0.0, 0.0),
+            width,
+            height,
+            z_index: 0,
+            image_id: 0,
+            placement_id: 0,
+            alternate: false,
+        }
+    }
+}
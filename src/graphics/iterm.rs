@@ -0,0 +1,102 @@
+use super::MAX_IMAGE_SCREENS;
+
+// A width or height given in `File=` arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+    Auto,
+    Cells(u32),
+    Pixels(u32),
+    // Of the width or height of the screen.
+    Percent(u32),
+}
+
+impl Length {
+    fn parse(value: &str) -> Option<Self> {
+        if value == "auto" {
+            return Some(Length::Auto);
+        }
+        let (number, length): (&str, fn(u32) -> Self) = if let Some(n) = value.strip_suffix("px") {
+            (n, Length::Pixels)
+        } else if let Some(n) = value.strip_suffix('%') {
+            (n, Length::Percent)
+        } else {
+            (value, Length::Cells)
+        };
+        number.parse().ok().map(length)
+    }
+
+    // The length in physical pixels, where `cell` is the size of a cell and
+    // `screen` that of the screen in the same direction.
+    fn resolve(self, cell: f64, screen: f64) -> Option<f64> {
+        let length = match self {
+            Length::Auto => return None,
+            Length::Cells(n) => f64::from(n) * cell,
+            Length::Pixels(n) => f64::from(n),
+            Length::Percent(n) => f64::from(n) * screen / 100.0,
+        };
+        Some(length.min(screen * MAX_IMAGE_SCREENS))
+    }
+}
+
+// `OSC 1337 ; File = key=value ; ... : base64`, as sent by iTerm2's imgcat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct File {
+    pub width: Length,
+    pub height: Length,
+    pub preserve_aspect_ratio: bool,
+    // Files that are not inline are downloads, which are not supported.
+    pub inline: bool,
+    pub data: Vec<u8>,
+}
+
+// Parses everything after `File=`. Unknown keys, such as the name and size,
+// are ignored.
+pub fn parse(args: &[u8]) -> Option<File> {
+    let colon = args.iter().position(|&b| b == b':')?;
+    let mut file = File {
+        width: Length::Auto,
+        height: Length::Auto,
+        preserve_aspect_ratio: true,
+        inline: false,
+        data: base64::decode(&args[colon + 1..]).ok()?,
+    };
+    let args = std::str::from_utf8(&args[..colon]).ok()?;
+    for pair in args.split(';') {
+        let mut parts = pair.splitn(2, '=');
+        let (key, value) = (parts.next()?, parts.next().unwrap_or(""));
+        match key {
+            "width" => file.width = Length::parse(value)?,
+            "height" => file.height = Length::parse(value)?,
+            "preserveAspectRatio" => file.preserve_aspect_ratio = value != "0",
+            "inline" => file.inline = value == "1",
+            _ => {}
+        }
+    }
+    Some(file)
+}
+
+// The size on screen in physical pixels of an image of `image` pixels. An
+// automatic size follows the image, but is kept within the screen width.
+pub fn size(file: &File, image: (f64, f64), cell: (f64, f64), screen: (f64, f64)) -> (f64, f64) {
+    let (image_width, image_height) = image;
+    let width = file.width.resolve(cell.0, screen.0);
+    let height = file.height.resolve(cell.1, screen.1);
+    match (width, height) {
+        (None, None) => {
+            let scale = (screen.0 / image_width).min(1.0);
+            (image_width * scale, image_height * scale)
+        }
+        (Some(width), None) if file.preserve_aspect_ratio => {
+            (width, image_height * width / image_width)
+        }
+        (None, Some(height)) if file.preserve_aspect_ratio => {
+            (image_width * height / image_height, height)
+        }
+        (Some(width), Some(height)) if file.preserve_aspect_ratio => {
+            // Fits the image in the box.
+            let scale = (width / image_width).min(height / image_height);
+            (image_width * scale, image_height * scale)
+        }
+        (width, height) => (width.unwrap_or(image_width), height.unwrap_or(image_height)),
+    }
+}
@@ -0,0 +1,383 @@
+use super::{decode_png, Bitmap, MAX_IMAGE_BYTES, MAX_IMAGE_SIZE};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// The most images kept at once, whatever their size. The oldest is dropped
+// to make room, as it is when they take more than `MAX_IMAGE_BYTES`.
+const MAX_IMAGES: usize = 256;
+
+// The largest payload accepted, after base64 decoding or decompression.
+const MAX_DATA_LEN: usize = MAX_IMAGE_SIZE as usize * MAX_IMAGE_SIZE as usize * 4;
+
+// The keys of `ESC _ G key=value,... ; payload ESC \`, with the defaults of
+// the protocol. Single character values are kept as bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub action: u8,
+    pub quiet: u32,
+    // Transmission
+    pub format: u32,
+    pub medium: u8,
+    pub compression: Option<u8>,
+    pub more: bool,
+    pub data_width: u32,
+    pub data_height: u32,
+    pub data_size: u64,
+    pub data_offset: u64,
+    pub image_id: u32,
+    pub image_number: u32,
+    // Placement
+    pub placement_id: u32,
+    // The part of the image to show, where a zero size means the rest of
+    // it. Deletion by cell uses `x` and `y` as 1-based cell coordinates.
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub offset: (u32, u32),
+    pub cols: u32,
+    pub rows: u32,
+    pub z_index: i32,
+    pub move_cursor: bool,
+    // Deletion
+    pub delete: u8,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self {
+            action: b't',
+            quiet: 0,
+            format: 32,
+            medium: b'd',
+            compression: None,
+            more: false,
+            data_width: 0,
+            data_height: 0,
+            data_size: 0,
+            data_offset: 0,
+            image_id: 0,
+            image_number: 0,
+            placement_id: 0,
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            offset: (0, 0),
+            cols: 0,
+            rows: 0,
+            z_index: 0,
+            move_cursor: true,
+            delete: b'a',
+        }
+    }
+}
+
+// Splits the body of a graphics command (after the `G`) into its keys and
+// its payload. Unknown keys are ignored.
+pub fn parse(data: &[u8]) -> Option<(Command, &[u8])> {
+    let (control, payload) = match data.iter().position(|&b| b == b';') {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &data[data.len()..]),
+    };
+    let mut command = Command::default();
+    for pair in control.split(|&b| b == b',').filter(|p| !p.is_empty()) {
+        let (key, value) = match pair {
+            [key, b'=', value @ ..] if !value.is_empty() => (*key, value),
+            _ => return None,
+        };
+        let byte = value[0];
+        let number = || std::str::from_utf8(value).ok()?.parse::<u32>().ok();
+        match key {
+            b'a' => command.action = byte,
+            b't' => command.medium = byte,
+            b'o' => command.compression = Some(byte),
+            b'd' => command.delete = byte,
+            b'z' => command.z_index = std::str::from_utf8(value).ok()?.parse().ok()?,
+            b'S' => command.data_size = u64::from(number()?),
+            b'O' => command.data_offset = u64::from(number()?),
+            _ => {
+                let n = number()?;
+                match key {
+                    b'q' => command.quiet = n,
+                    b'f' => command.format = n,
+                    b'm' => command.more = n == 1,
+                    b's' => command.data_width = n,
+                    b'v' => command.data_height = n,
+                    b'i' => command.image_id = n,
+                    b'I' => command.image_number = n,
+                    b'p' => command.placement_id = n,
+                    b'x' => command.x = n,
+                    b'y' => command.y = n,
+                    b'w' => command.width = n,
+                    b'h' => command.height = n,
+                    b'X' => command.offset.0 = n,
+                    b'Y' => command.offset.1 = n,
+                    b'c' => command.cols = n,
+                    b'r' => command.rows = n,
+                    b'C' => command.move_cursor = n != 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+    Some((command, payload))
+}
+
+// Transmitted images by id, and the upload in progress when the payload is
+// sent in chunks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Storage {
+    images: HashMap<u32, (u64, Arc<Bitmap>)>,
+    // The newest image for each image number.
+    numbers: HashMap<u32, u32>,
+    upload: Option<(Command, Vec<u8>)>,
+    // Counts transmissions, to find the oldest image.
+    serial: u64,
+}
+
+impl Storage {
+    // Adds a chunk to the upload in progress, or starts one. Returns the
+    // command and the whole payload once the last chunk has arrived.
+    pub fn receive(&mut self, command: Command, payload: &[u8]) -> Option<(Command, Vec<u8>)> {
+        let more = command.more;
+        let (first, mut data) = match self.upload.take() {
+            Some((first, mut data)) => {
+                data.extend_from_slice(payload);
+                (first, data)
+            }
+            None => (command, payload.to_vec()),
+        };
+        if data.len() > MAX_DATA_LEN / 3 * 4 {
+            return Some((first, vec![]));
+        }
+        if more {
+            self.upload = Some((first, data));
+            None
+        } else {
+            data.retain(|b| !b.is_ascii_whitespace());
+            Some((first, data))
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<Arc<Bitmap>> {
+        self.images.get(&id).map(|(_, bitmap)| bitmap.clone())
+    }
+
+    // The id of the newest image transmitted with the number.
+    pub fn by_number(&self, number: u32) -> Option<u32> {
+        self.numbers
+            .get(&number)
+            .copied()
+            .filter(|id| self.images.contains_key(id))
+    }
+
+    // Picks an id for an image that was given only a number.
+    pub fn unused_id(&self) -> u32 {
+        (1..).find(|id| !self.images.contains_key(id)).unwrap()
+    }
+
+    pub fn insert(&mut self, id: u32, number: u32, bitmap: Bitmap) {
+        self.images.remove(&id);
+        loop {
+            let bytes: usize = self.bitmaps().map(|bitmap| bitmap.pixels.len()).sum();
+            if self.images.len() < MAX_IMAGES && bytes + bitmap.pixels.len() <= MAX_IMAGE_BYTES {
+                break;
+            }
+            match self.oldest(|_, _| true) {
+                Some(oldest) => self.remove(oldest),
+                None => break,
+            }
+        }
+        self.serial += 1;
+        self.images.insert(id, (self.serial, Arc::new(bitmap)));
+        if number != 0 {
+            self.numbers.insert(number, id);
+        }
+    }
+
+    pub fn bitmaps(&self) -> impl Iterator<Item = &Arc<Bitmap>> {
+        self.images.values().map(|(_, bitmap)| bitmap)
+    }
+
+    // The id of the oldest image that `filter` accepts.
+    pub fn oldest(&self, filter: impl Fn(u32, &Arc<Bitmap>) -> bool) -> Option<u32> {
+        self.images
+            .iter()
+            .filter(|&(&id, (_, bitmap))| filter(id, bitmap))
+            .min_by_key(|(_, (serial, _))| *serial)
+            .map(|(&id, _)| id)
+    }
+
+    pub fn newest(&self) -> Option<u32> {
+        self.images
+            .iter()
+            .max_by_key(|(_, (serial, _))| *serial)
+            .map(|(&id, _)| id)
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.images.remove(&id);
+        self.numbers.retain(|_, i| *i != id);
+    }
+}
+
+// Turns a transmitted payload into an image, or a `CODE:message` error for
+// the response.
+pub fn load(command: &Command, payload: &[u8]) -> Result<Bitmap, String> {
+    let payload =
+        base64::decode(payload).map_err(|_| "EINVAL:payload is not base64".to_string())?;
+    let data = match command.medium {
+        b'd' => payload,
+        b'f' | b't' => read_file(command, &payload)?,
+        b's' => read_shared_memory(command, &payload)?,
+        _ => return Err("EINVAL:unknown transmission medium".to_string()),
+    };
+    let data = match command.compression {
+        None => data,
+        Some(b'z') => {
+            let mut inflated = vec![];
+            flate2::read::ZlibDecoder::new(&data[..])
+                .take(MAX_DATA_LEN as u64 + 1)
+                .read_to_end(&mut inflated)
+                .map_err(|_| "EINVAL:bad zlib data".to_string())?;
+            inflated
+        }
+        Some(_) => return Err("EINVAL:unknown compression".to_string()),
+    };
+    if data.len() > MAX_DATA_LEN {
+        return Err("EFBIG:image too large".to_string());
+    }
+    match command.format {
+        24 | 32 => {
+            let (width, height) = (command.data_width, command.data_height);
+            if width == 0 || height == 0 {
+                return Err("EINVAL:missing image size".to_string());
+            }
+            if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+                return Err("EFBIG:image too large".to_string());
+            }
+            let channels = command.format as usize / 8;
+            let len = width as usize * height as usize * channels;
+            if data.len() < len {
+                return Err("ENODATA:insufficient image data".to_string());
+            }
+            let mut bitmap = Bitmap::new(width, height);
+            if channels == 4 {
+                bitmap.pixels.copy_from_slice(&data[..len]);
+            } else {
+                for (rgba, rgb) in bitmap.pixels.chunks_mut(4).zip(data.chunks(3)) {
+                    rgba[..3].copy_from_slice(rgb);
+                    rgba[3] = 255;
+                }
+            }
+            Ok(bitmap)
+        }
+        100 => decode_png(&data).map_err(|e| format!("EBADPNG:{}", e)),
+        _ => Err("EINVAL:unknown format".to_string()),
+    }
+}
+
+fn read_file(command: &Command, path: &[u8]) -> Result<Vec<u8>, String> {
+    let path = std::str::from_utf8(path).map_err(|_| "EINVAL:bad file name".to_string())?;
+    let path = fs::canonicalize(path).map_err(|e| format!("EBADF:{}", e))?;
+    // Only regular files, so that a stray command cannot block on a FIFO or
+    // read from a device.
+    let metadata = fs::metadata(&path).map_err(|e| format!("EBADF:{}", e))?;
+    if !metadata.is_file() {
+        return Err("EINVAL:not a regular file".to_string());
+    }
+    let mut file = fs::File::open(&path).map_err(|e| format!("EBADF:{}", e))?;
+    file.seek(SeekFrom::Start(command.data_offset))
+        .map_err(|e| format!("EBADF:{}", e))?;
+    let limit = match command.data_size {
+        0 => MAX_DATA_LEN as u64 + 1,
+        size => size.min(MAX_DATA_LEN as u64 + 1),
+    };
+    let mut data = vec![];
+    file.take(limit)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("EBADF:{}", e))?;
+    if command.medium == b't' && is_temporary_file(&path) {
+        let _ = fs::remove_file(&path);
+    }
+    Ok(data)
+}
+
+// Temporary files are removed once read, but only those that are clearly
+// meant for this protocol: directly in a temporary directory, with the
+// marker in their name. Others are left in place, as kitty does. `path` is
+// canonical, so `..` and links cannot lead elsewhere.
+fn is_temporary_file(path: &Path) -> bool {
+    let marked = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains("tty-graphics-protocol"));
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return false,
+    };
+    let temporary = [env::temp_dir(), PathBuf::from("/dev/shm")]
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| dir == parent);
+    marked && temporary
+}
+
+#[cfg(unix)]
+fn read_shared_memory(command: &Command, name: &[u8]) -> Result<Vec<u8>, String> {
+    use std::ffi::CString;
+    use std::io::Error;
+
+    let name = CString::new(name).map_err(|_| "EINVAL:bad shared memory name".to_string())?;
+    unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0);
+        if fd < 0 {
+            return Err(format!("EBADF:{}", Error::last_os_error()));
+        }
+        let mut stat: libc::stat = std::mem::zeroed();
+        let result = if libc::fstat(fd, &mut stat) < 0 {
+            Err(format!("EBADF:{}", Error::last_os_error()))
+        } else {
+            let size = (stat.st_size as u64).min(MAX_DATA_LEN as u64 + 1) as usize;
+            let offset = (command.data_offset as usize).min(size);
+            let len = match command.data_size as usize {
+                0 => size - offset,
+                len => len.min(size - offset),
+            };
+            if size == 0 {
+                Ok(vec![])
+            } else {
+                let map = libc::mmap(
+                    std::ptr::null_mut(),
+                    size,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                );
+                if map == libc::MAP_FAILED {
+                    Err(format!("EBADF:{}", Error::last_os_error()))
+                } else {
+                    let bytes = std::slice::from_raw_parts(map as *const u8, size);
+                    let data = bytes[offset..offset + len].to_vec();
+                    libc::munmap(map, size);
+                    Ok(data)
+                }
+            }
+        };
+        libc::close(fd);
+        libc::shm_unlink(name.as_ptr());
+        result
+    }
+}
+
+#[cfg(windows)]
+fn read_shared_memory(_command: &Command, _name: &[u8]) -> Result<Vec<u8>, String> {
+    Err("EINVAL:shared memory is not supported".to_string())
+}
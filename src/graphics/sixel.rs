@@ -0,0 +1,242 @@
+use super::{Bitmap, MAX_IMAGE_SIZE};
+
+// The VT340 default colour registers, in percent.
+const DEFAULT_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (20, 20, 80),
+    (80, 13, 13),
+    (20, 80, 20),
+    (80, 20, 80),
+    (20, 80, 80),
+    (80, 80, 20),
+    (53, 53, 53),
+    (26, 26, 26),
+    (33, 33, 60),
+    (60, 26, 26),
+    (33, 60, 33),
+    (60, 33, 60),
+    (33, 60, 60),
+    (60, 60, 33),
+    (80, 80, 80),
+];
+
+const REGISTERS: usize = 256;
+
+// Marks the pixels that were not drawn.
+const UNSET: u16 = u16::MAX;
+
+fn percent((r, g, b): (u8, u8, u8)) -> [u8; 4] {
+    let scale = |v: u8| (u32::from(v.min(100)) * 255 / 100) as u8;
+    [scale(r), scale(g), scale(b), 255]
+}
+
+// Converts HLS (hue in degrees, lightness and saturation in percent) as used
+// by sixel, where a hue of 0 is blue.
+fn hls(h: u32, l: u32, s: u32) -> [u8; 4] {
+    let l = f64::from(l.min(100)) / 100.0;
+    let s = f64::from(s.min(100)) / 100.0;
+    let h = f64::from((h % 360 + 240) % 360) / 360.0;
+    let q = if l < 0.5 {
+        l * (1.0 + s)
+    } else {
+        l + s - l * s
+    };
+    let p = 2.0 * l - q;
+    let channel = |t: f64| {
+        let t = (t + 1.0) % 1.0;
+        let v = if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        };
+        (v * 255.0).round() as u8
+    };
+    [
+        channel(h + 1.0 / 3.0),
+        channel(h),
+        channel(h - 1.0 / 3.0),
+        255,
+    ]
+}
+
+struct Decoder {
+    colors: Vec<[u8; 4]>,
+    color: usize,
+    // The colour registers of the pixels so far, in rows of `stride`. The
+    // buffer grows geometrically as sixels arrive, and is cropped to the
+    // size of the image at the end.
+    pixels: Vec<u16>,
+    stride: usize,
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        let mut colors = vec![[0, 0, 0, 255]; REGISTERS];
+        for (color, &default) in colors.iter_mut().zip(&DEFAULT_COLORS) {
+            *color = percent(default);
+        }
+        Self {
+            colors,
+            color: 0,
+            pixels: vec![],
+            stride: 0,
+            width: 0,
+            height: 0,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    // Grows the image to at least `width` by `height`, which must be within
+    // `MAX_IMAGE_SIZE`.
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = self.width.max(width);
+        self.height = self.height.max(height);
+        let rows = self.pixels.len().checked_div(self.stride).unwrap_or(0);
+        if self.width <= self.stride && self.height <= rows {
+            return;
+        }
+        let max = MAX_IMAGE_SIZE as usize;
+        let grow = |used: usize, allocated: usize| {
+            if used > allocated {
+                used.max(allocated * 2).min(max)
+            } else {
+                allocated
+            }
+        };
+        let (stride, new_rows) = (grow(self.width, self.stride), grow(self.height, rows));
+        let mut pixels = vec![UNSET; stride * new_rows];
+        for y in 0..rows {
+            let row = &self.pixels[y * self.stride..(y + 1) * self.stride];
+            pixels[y * stride..y * stride + self.stride].copy_from_slice(row);
+        }
+        self.pixels = pixels;
+        self.stride = stride;
+    }
+
+    // Draws a sixel `n` times: the low 6 bits are a column of pixels.
+    fn sixel(&mut self, bits: u8, n: usize) {
+        let max = MAX_IMAGE_SIZE as usize;
+        let n = n.min(max.saturating_sub(self.x));
+        if n == 0 || self.y + 6 > max {
+            return;
+        }
+        if bits != 0 {
+            self.resize(self.x + n, self.y + 6);
+            for dy in 0..6 {
+                if bits & (1 << dy) == 0 {
+                    continue;
+                }
+                let start = (self.y + dy) * self.stride + self.x;
+                for pixel in &mut self.pixels[start..start + n] {
+                    *pixel = self.color as u16;
+                }
+            }
+        }
+        self.x += n;
+    }
+
+    fn finish(self, transparent: bool) -> Option<Bitmap> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let background = if transparent {
+            [0, 0, 0, 0]
+        } else {
+            self.colors[0]
+        };
+        let mut bitmap = Bitmap::new(self.width as u32, self.height as u32);
+        let pixels = self
+            .pixels
+            .chunks(self.stride)
+            .flat_map(|row| &row[..self.width]);
+        for (pixel, rgba) in pixels.zip(bitmap.pixels.chunks_mut(4)) {
+            let color = match *pixel {
+                UNSET => background,
+                color => self.colors[usize::from(color)],
+            };
+            rgba.copy_from_slice(&color);
+        }
+        Some(bitmap)
+    }
+}
+
+// Reads `;`-separated numbers from the start of `data`, returning them and
+// the number of bytes read.
+fn numbers(data: &[u8]) -> (Vec<u32>, usize) {
+    let mut numbers = vec![0u32];
+    let mut len = 0;
+    for &b in data {
+        match b {
+            b'0'..=b'9' => {
+                let last = numbers.last_mut().unwrap();
+                *last = last.saturating_mul(10).saturating_add(u32::from(b - b'0'));
+            }
+            b';' => numbers.push(0),
+            _ => break,
+        }
+        len += 1;
+    }
+    (numbers, len)
+}
+
+// Decodes the payload of `DCS P1 ; P2 ; P3 q ... ST`. Pixels that are not
+// drawn are transparent when P2 is 1, and colour register 0 otherwise.
+pub fn decode(params: &[Vec<i64>], data: &[u8]) -> Option<Bitmap> {
+    let transparent = params.get(1).and_then(|p| p.first()) == Some(&1);
+    let mut decoder = Decoder::new();
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        match b {
+            // Raster attributes: Pan ; Pad ; Ph ; Pv
+            b'"' => {
+                let (values, len) = numbers(&data[i..]);
+                i += len;
+                if let (Some(&width), Some(&height)) = (values.get(2), values.get(3)) {
+                    let max = MAX_IMAGE_SIZE;
+                    decoder.resize(width.min(max) as usize, height.min(max) as usize);
+                }
+            }
+            // Colour introducer: # Pc, or # Pc ; Pu ; Px ; Py ; Pz
+            b'#' => {
+                let (values, len) = numbers(&data[i..]);
+                i += len;
+                let register = values[0] as usize % REGISTERS;
+                if let [_, space, x, y, z] = values[..] {
+                    decoder.colors[register] = match space {
+                        1 => hls(x, y, z),
+                        _ => percent((x.min(100) as u8, y.min(100) as u8, z.min(100) as u8)),
+                    };
+                }
+                decoder.color = register;
+            }
+            // Repeat introducer: ! Pn sixel
+            b'!' => {
+                let (values, len) = numbers(&data[i..]);
+                i += len;
+                if let Some(&bits @ b'?'..=b'~') = data.get(i) {
+                    i += 1;
+                    decoder.sixel(bits - b'?', values[0].max(1) as usize);
+                }
+            }
+            b'$' => decoder.x = 0,
+            b'-' => {
+                decoder.x = 0;
+                decoder.y += 6;
+            }
+            b'?'..=b'~' => decoder.sixel(b - b'?', 1),
+            _ => {}
+        }
+    }
+    decoder.finish(transparent)
+}
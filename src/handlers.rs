@@ -2,53 +2,238 @@ use crate::action::Action;
 use crate::display;
 use crate::pty;
 
+use std::process::Command;
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// DCS payloads are dropped beyond this size.
+const MAX_DCS_LEN: usize = 16 << 20;
+
+// The parameters, intermediates, ignore flag and final character of a DCS
+// sequence, followed by its payload.
+type Dcs = (Vec<Vec<i64>>, Vec<u8>, bool, char, Vec<u8>);
 
 pub struct PtyHandler {
     pub tx: Sender<Action>,
+    // The DCS sequence being received.
+    dcs: Option<Dcs>,
 }
 
-impl pty::Handler for PtyHandler {
+impl PtyHandler {
+    pub fn new(tx: Sender<Action>) -> Self {
+        Self { tx, dcs: None }
+    }
+}
+
+fn to_params(params: &pty::Params) -> Vec<Vec<i64>> {
+    params
+        .iter()
+        .map(|param| param.iter().map(|&p| i64::from(p)).collect())
+        .collect()
+}
+
+impl pty::Perform for PtyHandler {
     fn print(&mut self, c: char) {
         self.tx.send(Action::Print(c)).unwrap();
     }
-    fn csi_dispatch(&mut self, params: &[i64], intermediates: &[u8], ignore: bool, c: char) {
-        let action = Action::DispatchCSI(params.to_vec(), intermediates.to_vec(), ignore, c);
+    fn csi_dispatch(&mut self, params: &pty::Params, intermediates: &[u8], ignore: bool, c: char) {
+        let params = to_params(params);
+        let action = Action::DispatchCSI(params, intermediates.to_vec(), ignore, c);
         self.tx.send(action).unwrap()
     }
     fn execute(&mut self, byte: u8) {
         self.tx.send(Action::Execute(byte)).unwrap();
     }
-    fn hook(&mut self, _params: &[i64], _intermediates: &[u8], _ignore: bool) {}
-    fn put(&mut self, _byte: u8) {}
-    fn unhook(&mut self) {}
-    fn osc_dispatch(&mut self, _params: &[&[u8]]) {}
-    fn esc_dispatch(&mut self, _params: &[i64], _intermediates: &[u8], _ignore: bool, _byte: u8) {}
+    fn hook(&mut self, params: &pty::Params, intermediates: &[u8], ignore: bool, c: char) {
+        let params = to_params(params);
+        self.dcs = Some((params, intermediates.to_vec(), ignore, c, vec![]));
+    }
+    fn put(&mut self, byte: u8) {
+        if let Some((.., data)) = &mut self.dcs {
+            if data.len() < MAX_DCS_LEN {
+                data.push(byte);
+            }
+        }
+    }
+    fn unhook(&mut self) {
+        if let Some((params, intermediates, ignore, c, data)) = self.dcs.take() {
+            let action = Action::DispatchDCS(params, intermediates, ignore, c, data);
+            self.tx.send(action).unwrap()
+        }
+    }
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let params = params.iter().map(|param| param.to_vec()).collect();
+        let action = Action::DispatchOSC(params, bell_terminated);
+        self.tx.send(action).unwrap()
+    }
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        let action = Action::DispatchESC(intermediates.to_vec(), ignore, byte);
+        self.tx.send(action).unwrap()
+    }
+}
+
+impl pty::Handler for PtyHandler {
+    fn apc_dispatch(&mut self, data: &[u8]) {
+        self.tx.send(Action::DispatchAPC(data.to_vec())).unwrap()
+    }
 }
 
 pub struct DisplayHandler {
     pub pty: pty::Pty,
     pub tx: Sender<Action>,
+    // The URI of the hyperlink under the mouse pointer, kept up to date by
+    // the main loop.
+    pub hovered_link: Arc<Mutex<Option<String>>>,
+    // The command that opens hyperlinks, e.g. `xdg-open`.
+    pub opener: String,
+    // Set when a key binding consumed a key press, whose character must not
+    // reach the pty. Only the character that follows the press is dropped:
+    // any other key event ends the suppression, for presses without one.
+    pub suppress_character: bool,
+}
+
+impl DisplayHandler {
+    fn open_hovered_link(&self) {
+        let uri = match self.hovered_link.lock().unwrap().clone() {
+            Some(uri) => uri,
+            None => return,
+        };
+        match Command::new(&self.opener).arg(&uri).spawn() {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => eprintln!("failed to open {}: {}", uri, e),
+        }
+    }
 }
 
 impl display::Handler for DisplayHandler {
     fn on_window_event(&mut self, event: &display::WindowEvent) {
         use glium::glutin::*;
+        if let WindowEvent::KeyboardInput { .. } = event {
+            self.suppress_character = false;
+        }
         match event {
             WindowEvent::CloseRequested => self.tx.send(Action::Close()).unwrap(),
+            WindowEvent::Resized(_) => self.tx.send(Action::Redraw()).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Escape),
+                        ..
+                    },
+                ..
+            } => self.tx.send(Action::Close()).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::PageUp),
+                        modifiers: ModifiersState { shift: true, .. },
+                        ..
+                    },
+                ..
+            } => self.tx.send(Action::ScrollPages(1)).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::PageDown),
+                        modifiers: ModifiersState { shift: true, .. },
+                        ..
+                    },
+                ..
+            } => self.tx.send(Action::ScrollPages(-1)).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Up),
+                        modifiers:
+                            ModifiersState {
+                                ctrl: true,
+                                shift: true,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => self.tx.send(Action::JumpToPrompt(-1)).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Down),
+                        modifiers:
+                            ModifiersState {
+                                ctrl: true,
+                                shift: true,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => self.tx.send(Action::JumpToPrompt(1)).unwrap(),
             WindowEvent::KeyboardInput {
                 input:
                     KeyboardInput {
                         state: ElementState::Pressed,
-                        virtual_keycode: Some(keypress),
+                        virtual_keycode: Some(VirtualKeyCode::End),
+                        modifiers:
+                            ModifiersState {
+                                ctrl: true,
+                                shift: true,
+                                ..
+                            },
                         ..
                     },
                 ..
-            } => match keypress {
-                VirtualKeyCode::Escape => self.tx.send(Action::Close()).unwrap(),
-                _ => (),
-            },
+            } => self.tx.send(Action::SelectLastOutput()).unwrap(),
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::N),
+                        modifiers:
+                            ModifiersState {
+                                ctrl: true,
+                                shift: true,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => {
+                self.suppress_character = true;
+                self.tx.send(Action::NewWindow()).unwrap();
+            }
+            WindowEvent::ReceivedCharacter(_) if self.suppress_character => {
+                self.suppress_character = false;
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => (y * 3.0) as i64,
+                    MouseScrollDelta::PixelDelta(p) => (p.y / 16.0) as i64,
+                };
+                if lines != 0 {
+                    self.tx.send(Action::ScrollLines(lines)).unwrap();
+                }
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let action = Action::MouseMoved(position.x, position.y);
+                self.tx.send(action).unwrap();
+            }
+            WindowEvent::CursorLeft { .. } => self.tx.send(Action::MouseLeft()).unwrap(),
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                modifiers: ModifiersState { ctrl: true, .. },
+                ..
+            } => self.open_hovered_link(),
             WindowEvent::ReceivedCharacter(c) => {
+                self.tx.send(Action::ScrollToBottom()).unwrap();
                 let mut bytes = [0; 4];
                 let s = c.encode_utf8(&mut bytes);
                 for b in s.bytes() {
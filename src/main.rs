@@ -1,13 +1,26 @@
 mod action;
+mod charset;
+mod clipboard;
 mod display;
+mod graphics;
 mod handlers;
+mod mode;
+mod notify;
+mod palette;
 mod pty;
 mod state;
 mod store;
+mod terminfo;
 mod update;
 
+use std::env;
 use std::error::Error;
-use std::sync::mpsc::channel;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use action::Action;
 use handlers::{DisplayHandler, PtyHandler};
@@ -15,37 +28,263 @@ use state::State;
 use store::Store;
 use update::update;
 
-fn main() -> Result<(), Box<Error>> {
+// The directory that a new window starts the shell in.
+const START_DIRECTORY: &str = "GREENTTY_CWD";
+
+fn main() -> Result<(), Box<dyn Error>> {
     let cols = 80;
     let rows = 24;
+    let scrollback = scrollback_limit();
     let (tx, rx) = channel::<Action>();
 
+    let default_shell = pty::Config::default().shell;
+    let shell = if cfg!(unix) {
+        env::var("SHELL").unwrap_or_else(|_| default_shell.to_string())
+    } else {
+        default_shell.to_string()
+    };
+    // New windows are told where to start the shell with GREENTTY_CWD. The
+    // process itself stays where it was started, which is where the fonts
+    // are found. The variable is not passed on, so that a window started
+    // from the shell opens where it is run.
+    let cwd = env::var_os(START_DIRECTORY)
+        .map(PathBuf::from)
+        .or_else(|| env::current_dir().ok())
+        .map(|cwd| cwd.to_string_lossy().into_owned())
+        .unwrap_or_else(|| pty::Config::default().cwd.to_string());
+    env::remove_var(START_DIRECTORY);
     let pty = pty::Pty::spawn(
         &pty::Config {
-            shell: "powershell",
+            shell: &shell,
             cols,
             rows,
-            ..Default::default()
+            cwd: &cwd,
         },
-        PtyHandler { tx: tx.clone() },
+        PtyHandler::new(tx.clone()),
     )?;
+    let hovered_link = Arc::new(Mutex::new(None));
+    let opener = env::var("GREENTTY_OPENER").unwrap_or_else(|_| {
+        if cfg!(windows) {
+            "explorer".to_string()
+        } else if cfg!(target_os = "macos") {
+            "open".to_string()
+        } else {
+            "xdg-open".to_string()
+        }
+    });
     let mut display = display::Display::open(DisplayHandler {
         pty: pty.clone(),
         tx: tx.clone(),
+        hovered_link: hovered_link.clone(),
+        opener,
+        suppress_character: false,
     })?;
-    let mut store = Store::new(update, State::new());
+    let mut clipboard = clipboard::Clipboard::new(clipboard_provider(), clipboard_config());
+    // Requests are confirmed one at a time, away from the main loop, so that
+    // the screen keeps up with the pty while a dialog is open.
+    let (questions, pending_questions) = channel::<clipboard::Request>();
+    let answers = tx.clone();
+    thread::spawn(move || {
+        for request in pending_questions {
+            let allowed = confirm_clipboard_request(&request);
+            if answers
+                .send(Action::ClipboardRequestAnswered(request, allowed))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    // GREENTTY_NOTIFIER is run with `--`, a title and a body for each
+    // notification.
+    let notifier = notify::CommandNotifier {
+        program: env::var("GREENTTY_NOTIFIER").unwrap_or_else(|_| "notify-send".to_string()),
+    };
+    let mut notifications = notify::Notifications::new(Box::new(notifier), Duration::from_secs(2));
+    let mut store = Store::new(update, State::new(cols as usize, rows as usize, scrollback));
 
     loop {
-        let action = rx.recv()?;
-        if let Action::Close() = action {
-            break;
+        // Wakes up to redraw when the screen changes by itself, when the
+        // visual bell ends, or when an application has held back rendering
+        // for too long.
+        let state = store.get_state();
+        let now = Instant::now();
+        let next_redraw = display
+            .next_redraw()
+            .into_iter()
+            .chain(state.flash_until)
+            .chain(state.synchronized_output_deadline())
+            .filter(|&deadline| deadline > now)
+            .min();
+        let action = match next_redraw {
+            Some(at) => match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                Ok(action) => action,
+                Err(RecvTimeoutError::Timeout) => Action::Redraw(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => rx.recv()?,
+        };
+        match action {
+            Action::Close() => break,
+            Action::NewWindow() => {
+                open_window(store.get_state(), &pty);
+                continue;
+            }
+            Action::ClipboardRequestAnswered(request, allowed) => {
+                if allowed {
+                    if let Some(reply) = clipboard.carry_out(&request) {
+                        for b in reply.bytes() {
+                            pty.write(b)?;
+                        }
+                    }
+                }
+                continue;
+            }
+            _ => {}
         }
         store.dispatch(&action);
         for action in rx.try_iter() {
             store.dispatch(&action);
         }
-        display.render(store.get_state())?;
+        let (cols, rows) = display.grid_size();
+        let state = store.get_state();
+        if (cols, rows) != (state.cols, state.rows) {
+            pty.resize(cols as u32, rows as u32)?;
+            store.dispatch(&Action::Resize(cols, rows));
+        }
+        let (width, height) = display.cell_size();
+        let dpi_factor = display.dpi_factor();
+        let state = store.get_state();
+        if ((width, height), dpi_factor) != (state.cell_size, state.dpi_factor) {
+            store.dispatch(&Action::SetCellSize(width, height, dpi_factor));
+        }
+        let state = store.get_state();
+        if !state.responses.is_empty() {
+            for b in &state.responses {
+                pty.write(*b)?;
+            }
+            store.dispatch(&Action::ResponsesSent());
+        }
+        let state = store.get_state();
+        if !state.clipboard_requests.is_empty() {
+            for request in &state.clipboard_requests {
+                match clipboard.policy(request) {
+                    clipboard::Policy::Allow => {
+                        if let Some(reply) = clipboard.carry_out(request) {
+                            for b in reply.bytes() {
+                                pty.write(b)?;
+                            }
+                        }
+                    }
+                    clipboard::Policy::Deny => {}
+                    clipboard::Policy::Ask => {
+                        let _ = questions.send(request.clone());
+                    }
+                }
+            }
+            store.dispatch(&Action::ClipboardRequestsHandled());
+        }
+        let state = store.get_state();
+        if state.bell || !state.notifications.is_empty() {
+            let now = Instant::now();
+            if state.bell {
+                // A bell in the background usually means a job is done.
+                if !display.is_focused() {
+                    display.request_attention();
+                    let bell = notify::Notification {
+                        title: state.title.clone(),
+                        body: "Bell".to_string(),
+                    };
+                    notifications.notify(&bell, now);
+                }
+            }
+            for notification in &state.notifications {
+                notifications.notify(notification, now);
+            }
+            store.dispatch(&Action::NotificationsHandled());
+        }
+        let state = store.get_state();
+        *hovered_link.lock().unwrap() = state
+            .hovered_hyperlink()
+            .and_then(|number| state.hyperlinks.get(&number))
+            .map(|link| link.uri.clone());
+        match state.synchronized_output_deadline() {
+            Some(deadline) if Instant::now() < deadline => {}
+            _ => display.render(state)?,
+        }
     }
 
     Ok(())
 }
+
+// Starts another instance in the directory the shell reported with OSC 7,
+// or where the foreground program is.
+fn open_window(state: &State, pty: &pty::Pty) {
+    let cwd = state
+        .cwd
+        .as_ref()
+        .map(PathBuf::from)
+        .filter(|cwd| cwd.is_dir())
+        .or_else(|| pty.foreground_cwd());
+    let mut command = match env::current_exe() {
+        Ok(exe) => Command::new(exe),
+        Err(e) => {
+            eprintln!("failed to open a new window: {}", e);
+            return;
+        }
+    };
+    if let Some(cwd) = cwd {
+        command.env(START_DIRECTORY, cwd);
+    }
+    match command.spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("failed to open a new window: {}", e),
+    }
+}
+
+fn clipboard_provider() -> Box<dyn clipboard::Provider> {
+    if cfg!(unix) && env::var_os("DISPLAY").is_some() {
+        Box::new(clipboard::XclipProvider)
+    } else {
+        Box::new(clipboard::MemoryProvider::default())
+    }
+}
+
+// GREENTTY_SCROLLBACK sets how many lines of history are kept, 0 for none.
+fn scrollback_limit() -> usize {
+    env::var("GREENTTY_SCROLLBACK")
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
+        .unwrap_or(10_000)
+}
+
+// Reads the OSC 52 policies from GREENTTY_CLIPBOARD_WRITE and
+// GREENTTY_CLIPBOARD_READ, each one of `allow`, `deny` or `ask`.
+fn clipboard_config() -> clipboard::Config {
+    let policy = |name: &str, default| {
+        env::var(name)
+            .ok()
+            .and_then(|value| clipboard::Policy::parse(&value))
+            .unwrap_or(default)
+    };
+    let default = clipboard::Config::default();
+    clipboard::Config {
+        write: policy("GREENTTY_CLIPBOARD_WRITE", default.write),
+        read: policy("GREENTTY_CLIPBOARD_READ", default.read),
+    }
+}
+
+// Asks with a zenity dialog, denying the request if it cannot be shown.
+fn confirm_clipboard_request(request: &clipboard::Request) -> bool {
+    let text = match request {
+        clipboard::Request::Set(..) => "Allow the application to set the clipboard?",
+        clipboard::Request::Query(..) => "Allow the application to read the clipboard?",
+    };
+    Command::new("zenity")
+        .args(["--question", "--title", "GreenTTY", "--text", text])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
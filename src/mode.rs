@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    // ANSI modes (`CSI Pm h`)
+    Insert,
+    LineFeedNewLine,
+    // DEC private modes (`CSI ? Pm h`)
+    CursorKeys,
+    ReverseVideo,
+    Origin,
+    Autowrap,
+    X10Mouse,
+    CursorBlink,
+    CursorVisible,
+    MouseClick,
+    MouseDrag,
+    MouseMotion,
+    FocusEvents,
+    Utf8Mouse,
+    SgrMouse,
+    AlternateScroll,
+    UrxvtMouse,
+    BracketedPaste,
+    SynchronizedOutput,
+}
+
+impl Mode {
+    pub fn from_ansi(n: i64) -> Option<Self> {
+        match n {
+            4 => Some(Mode::Insert),
+            20 => Some(Mode::LineFeedNewLine),
+            _ => None,
+        }
+    }
+
+    pub fn from_private(n: i64) -> Option<Self> {
+        match n {
+            1 => Some(Mode::CursorKeys),
+            5 => Some(Mode::ReverseVideo),
+            6 => Some(Mode::Origin),
+            7 => Some(Mode::Autowrap),
+            9 => Some(Mode::X10Mouse),
+            12 => Some(Mode::CursorBlink),
+            25 => Some(Mode::CursorVisible),
+            1000 => Some(Mode::MouseClick),
+            1002 => Some(Mode::MouseDrag),
+            1003 => Some(Mode::MouseMotion),
+            1004 => Some(Mode::FocusEvents),
+            1005 => Some(Mode::Utf8Mouse),
+            1006 => Some(Mode::SgrMouse),
+            1007 => Some(Mode::AlternateScroll),
+            1015 => Some(Mode::UrxvtMouse),
+            2004 => Some(Mode::BracketedPaste),
+            2026 => Some(Mode::SynchronizedOutput),
+            _ => None,
+        }
+    }
+}
+
+// The set of modes that are currently enabled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Modes {
+    enabled: HashSet<Mode>,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        let enabled = [Mode::Autowrap, Mode::CursorVisible]
+            .iter()
+            .cloned()
+            .collect();
+        Self { enabled }
+    }
+}
+
+impl Modes {
+    pub fn get(&self, mode: Mode) -> bool {
+        self.enabled.contains(&mode)
+    }
+
+    pub fn set(&mut self, mode: Mode, enable: bool) {
+        if enable {
+            self.enabled.insert(mode);
+        } else {
+            self.enabled.remove(&mode);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A desktop notification requested by OSC 9 or 777, or sent for a bell.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    // May be empty, in which case the notifier picks one.
+    pub title: String,
+    pub body: String,
+}
+
+pub trait Notifier {
+    fn notify(&mut self, notification: &Notification);
+}
+
+// Runs a command such as `notify-send` with the title and body as arguments,
+// after `--` so that they are never taken for options.
+pub struct CommandNotifier {
+    pub program: String,
+}
+
+impl CommandNotifier {
+    fn arguments(notification: &Notification) -> [&str; 3] {
+        let title = if notification.title.is_empty() {
+            "GreenTTY"
+        } else {
+            &notification.title
+        };
+        ["--", title, &notification.body]
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&mut self, notification: &Notification) {
+        match Command::new(&self.program)
+            .args(Self::arguments(notification))
+            .spawn()
+        {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => eprintln!("failed to run {}: {}", self.program, e),
+        }
+    }
+}
+
+// Drops notifications that arrive less than `interval` after the last one
+// shown, so that a flood of bells does not flood the desktop.
+pub struct Notifications {
+    notifier: Box<dyn Notifier>,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Notifications {
+    pub fn new(notifier: Box<dyn Notifier>, interval: Duration) -> Self {
+        Self {
+            notifier,
+            interval,
+            last: None,
+        }
+    }
+
+    // Returns whether the notification was shown.
+    pub fn notify(&mut self, notification: &Notification, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            if now.duration_since(last) < self.interval {
+                return false;
+            }
+        }
+        self.last = Some(now);
+        self.notifier.notify(notification);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct RecordingNotifier {
+        notifications: Rc<RefCell<Vec<Notification>>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&mut self, notification: &Notification) {
+            self.notifications.borrow_mut().push(notification.clone());
+        }
+    }
+
+    fn notification(body: &str) -> Notification {
+        Notification {
+            title: String::new(),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn notifications_are_rate_limited() {
+        let recorder = RecordingNotifier::default();
+        let interval = Duration::from_secs(1);
+        let mut notifications = Notifications::new(Box::new(recorder.clone()), interval);
+        let start = Instant::now();
+        assert!(notifications.notify(&notification("a"), start));
+        assert!(!notifications.notify(&notification("b"), start + interval / 2));
+        assert!(notifications.notify(&notification("c"), start + interval));
+        assert_eq!(
+            *recorder.notifications.borrow(),
+            vec![notification("a"), notification("c")]
+        );
+    }
+
+    #[test]
+    fn command_arguments_cannot_be_options() {
+        assert_eq!(
+            CommandNotifier::arguments(&notification("--help")),
+            ["--", "GreenTTY", "--help"]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+pub type Rgb = (u8, u8, u8);
+
+// The colours that applications may change with OSC 4, 10, 11 and 12.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: [Rgb; 256],
+    pub foreground: Rgb,
+    pub background: Rgb,
+    // `None` draws the cursor in the foreground colour.
+    pub cursor: Option<Rgb>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        let mut colors = [(0, 0, 0); 256];
+        for (n, color) in colors.iter_mut().enumerate() {
+            *color = default_color(n as u8);
+        }
+        Self {
+            colors,
+            foreground: (0xff, 0xff, 0xff),
+            background: (0x00, 0x00, 0x00),
+            cursor: None,
+        }
+    }
+}
+
+impl Palette {
+    pub fn reset_color(&mut self, n: u8) {
+        self.colors[n as usize] = default_color(n);
+    }
+}
+
+// The xterm 256-colour palette.
+pub fn default_color(n: u8) -> Rgb {
+    const ANSI: [Rgb; 16] = [
+        (0x00, 0x00, 0x00),
+        (0xcd, 0x00, 0x00),
+        (0x00, 0xcd, 0x00),
+        (0xcd, 0xcd, 0x00),
+        (0x00, 0x00, 0xee),
+        (0xcd, 0x00, 0xcd),
+        (0x00, 0xcd, 0xcd),
+        (0xe5, 0xe5, 0xe5),
+        (0x7f, 0x7f, 0x7f),
+        (0xff, 0x00, 0x00),
+        (0x00, 0xff, 0x00),
+        (0xff, 0xff, 0x00),
+        (0x5c, 0x5c, 0xff),
+        (0xff, 0x00, 0xff),
+        (0x00, 0xff, 0xff),
+        (0xff, 0xff, 0xff),
+    ];
+    match n {
+        0..=15 => ANSI[n as usize],
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let n = n - 16;
+            (level(n / 36), level(n / 6 % 6), level(n % 6))
+        }
+        _ => {
+            let v = 8 + (n - 232) * 10;
+            (v, v, v)
+        }
+    }
+}
+
+// Parses an X11 colour specification, either `rgb:r/g/b` with 1 to 4 hex
+// digits per component or `#rgb` with 1 to 4 digits per component.
+pub fn parse_color(spec: &str) -> Option<Rgb> {
+    let scale = |digits: &str| {
+        if digits.is_empty() || digits.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(digits, 16).ok()?;
+        let max = (1u32 << (4 * digits.len())) - 1;
+        Some((value * 255 / max) as u8)
+    };
+    if let Some(rest) = spec.strip_prefix("rgb:") {
+        let mut parts = rest.split('/');
+        let r = scale(parts.next()?)?;
+        let g = scale(parts.next()?)?;
+        let b = scale(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some((r, g, b))
+    } else if let Some(hex) = spec.strip_prefix('#') {
+        if hex.is_empty() || hex.len() % 3 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        let n = hex.len() / 3;
+        Some((
+            scale(&hex[..n])?,
+            scale(&hex[n..2 * n])?,
+            scale(&hex[2 * n..])?,
+        ))
+    } else {
+        None
+    }
+}
+
+// Formats a colour the way xterm reports it, with 16 bits per component.
+pub fn format_color((r, g, b): Rgb) -> String {
+    format!(
+        "rgb:{:04x}/{:04x}/{:04x}",
+        u16::from(r) * 0x101,
+        u16::from(g) * 0x101,
+        u16::from(b) * 0x101
+    )
+}
@@ -1,60 +1,101 @@
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(unix)]
+use self::unix as sys;
+#[cfg(windows)]
+use self::windows as sys;
+
 use std::error::Error;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::mem;
-use std::os::windows::io::{FromRawHandle, RawHandle};
-use std::ptr::{null, null_mut};
-use std::sync::mpsc::{channel, RecvError, Sender};
-use std::thread;
-
-use widestring::U16CString;
-use winapi::shared::basetsd::{PSIZE_T, SIZE_T};
-use winapi::shared::minwindef::BYTE;
-use winapi::shared::ntdef::LPWSTR;
-use winapi::um::consoleapi::{ClosePseudoConsole, CreatePseudoConsole, ResizePseudoConsole};
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::namedpipeapi::CreatePipe;
-use winapi::um::processthreadsapi::{
-    CreateProcessW, InitializeProcThreadAttributeList, UpdateProcThreadAttribute,
-    PROCESS_INFORMATION, STARTUPINFOW,
-};
-use winapi::um::winbase::{EXTENDED_STARTUPINFO_PRESENT, STARTUPINFOEXW};
-use winapi::um::wincontypes::{COORD, HPCON};
-use winapi::um::winnt::HANDLE;
-
-use vte::Parser;
-pub use vte::Perform as Handler;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
 
-#[derive(Clone, Debug)]
-pub struct Pty {
-    tx: Sender<Action>,
+pub use vte::{Params, Perform};
+
+// APC strings longer than this are dropped.
+const MAX_APC_LEN: usize = 16 << 20;
+
+// Receives the output of the program. vte ignores APC strings, so they are
+// passed to `apc_dispatch` instead.
+pub trait Handler: Perform {
+    fn apc_dispatch(&mut self, data: &[u8]);
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum Action {
-    Resize(i16, i16),
-    Write(u8),
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ApcState {
+    Ground,
+    // After an ESC that may start an APC string.
+    Escape,
+    Apc,
+    // After an ESC inside an APC string, which ends it.
+    ApcEscape,
 }
 
-struct PtyInner {
-    handle: HPCON,
-    pipe_in: HANDLE,
-    pipe_out: HANDLE,
+// Takes APC strings (`ESC _ ... ESC \`) out of the output and feeds the
+// rest to vte.
+pub struct Parser {
+    vte: vte::Parser,
+    state: ApcState,
+    apc: Vec<u8>,
 }
 
-// TODO: is it correct?
-unsafe impl Send for PtyInner {}
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            vte: vte::Parser::new(),
+            state: ApcState::Ground,
+            apc: vec![],
+        }
+    }
 
-impl Drop for PtyInner {
-    fn drop(&mut self) {
-        unsafe {
-            CloseHandle(self.pipe_in);
-            CloseHandle(self.pipe_out);
-            ClosePseudoConsole(self.handle);
+    pub fn advance<H: Handler>(&mut self, handler: &mut H, byte: u8) {
+        match (self.state, byte) {
+            (ApcState::Ground, 0x1b) => self.state = ApcState::Escape,
+            (ApcState::Ground, _) => self.vte.advance(handler, byte),
+            (ApcState::Escape, b'_') => {
+                self.apc.clear();
+                self.state = ApcState::Apc;
+            }
+            (ApcState::Escape, _) => {
+                self.state = ApcState::Ground;
+                self.vte.advance(handler, 0x1b);
+                self.advance(handler, byte);
+            }
+            (ApcState::Apc, 0x1b) => self.state = ApcState::ApcEscape,
+            (ApcState::Apc, _) => {
+                if self.apc.len() < MAX_APC_LEN {
+                    self.apc.push(byte);
+                }
+            }
+            (ApcState::ApcEscape, _) => {
+                // Any ESC ends the string, as it does in vte, but only ST
+                // is consumed with it.
+                handler.apc_dispatch(&self.apc);
+                self.apc.clear();
+                self.state = ApcState::Ground;
+                if byte != b'\\' {
+                    self.advance(handler, 0x1b);
+                    self.advance(handler, byte);
+                }
+            }
         }
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Pty {
+    tx: Sender<Action>,
+    process: sys::Process,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    Resize(i16, i16),
+    Write(u8),
+}
+
 pub struct Config<'a> {
     pub shell: &'a str,
     pub cols: u32,
@@ -62,6 +103,7 @@ pub struct Config<'a> {
     pub cwd: &'a str,
 }
 
+#[cfg(windows)]
 impl<'a> Default for Config<'a> {
     fn default() -> Self {
         Self {
@@ -73,120 +115,163 @@ impl<'a> Default for Config<'a> {
     }
 }
 
+#[cfg(unix)]
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self {
+            shell: "/bin/sh",
+            cols: 80,
+            rows: 24,
+            cwd: "/",
+        }
+    }
+}
+
 impl Pty {
-    pub fn resize(&self, cols: u32, rows: u32) -> Result<(), Box<Error>> {
+    pub fn resize(&self, cols: u32, rows: u32) -> Result<(), Box<dyn Error>> {
         self.tx.send(Action::Resize(cols as i16, rows as i16))?;
         Ok(())
     }
 
-    pub fn write(&self, b: u8) -> Result<(), Box<Error>> {
+    pub fn write(&self, b: u8) -> Result<(), Box<dyn Error>> {
         self.tx.send(Action::Write(b))?;
         Ok(())
     }
 
+    // Where the program in the foreground is, for when the shell does not
+    // report its working directory with OSC 7.
+    pub fn foreground_cwd(&self) -> Option<PathBuf> {
+        self.process.foreground_cwd()
+    }
+
     pub fn spawn<H: Handler + Send + 'static>(
         config: &Config,
-        mut handler: H,
-    ) -> Result<Self, Box<Error>> {
+        handler: H,
+    ) -> Result<Self, Box<dyn Error>> {
         let (tx, rx) = channel::<Action>();
+        let process = sys::spawn(config, handler, rx)?;
+        Ok(Pty { tx, process })
+    }
+}
 
-        let mut pipe_in: HANDLE = null_mut();
-        let mut pipe_out: HANDLE = null_mut();
-        let mut pipe_pty_in: HANDLE = null_mut();
-        let mut pipe_pty_out: HANDLE = null_mut();
-        let mut handle = null_mut();
-
-        // TODO(seikichi): Reduce unsafe block and close resources correctly.
-        unsafe {
-            CreatePipe(&mut pipe_pty_in, &mut pipe_in, null_mut(), 0);
-            CreatePipe(&mut pipe_out, &mut pipe_pty_out, null_mut(), 0);
-            CreatePseudoConsole(
-                COORD {
-                    X: config.cols as i16,
-                    Y: config.rows as i16,
-                },
-                pipe_pty_in,
-                pipe_pty_out,
-                0,
-                &mut handle,
-            );
-
-            // TODO(seikichi): modify drop to delete STARTUPINFOEXW and lpAttributeList
-            let mut si_ex: STARTUPINFOEXW = { mem::zeroed() };
-            si_ex.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as u32;
-
-            let mut size: SIZE_T = 0;
-            InitializeProcThreadAttributeList(null_mut(), 1, 0, &mut size as PSIZE_T);
-
-            let mut attr_list: Box<[BYTE]> = vec![0; size].into_boxed_slice();
-            si_ex.lpAttributeList = attr_list.as_mut_ptr() as _;
-
-            InitializeProcThreadAttributeList(si_ex.lpAttributeList, 1, 0, &mut size as PSIZE_T);
-            UpdateProcThreadAttribute(
-                si_ex.lpAttributeList,
-                0,
-                22 | 0x0002_0000, // PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE
-                handle,
-                mem::size_of::<HPCON>(),
-                null_mut(),
-                null_mut(),
-            );
-
-            let mut proc_info: PROCESS_INFORMATION = { mem::zeroed() };
-
-            CreateProcessW(
-                null(),
-                U16CString::from_str(config.shell).unwrap().as_ptr() as LPWSTR,
-                null_mut(),
-                null_mut(),
-                false as i32,
-                EXTENDED_STARTUPINFO_PRESENT,
-                null_mut(),
-                U16CString::from_str(config.cwd).unwrap().as_ptr(),
-                &mut si_ex.StartupInfo as *mut STARTUPINFOW,
-                &mut proc_info as *mut PROCESS_INFORMATION,
-            );
-
-            let mut file_in = File::from_raw_handle(pipe_in as RawHandle);
-            let mut file_out = File::from_raw_handle(pipe_out as RawHandle);
-
-            let mut parser = Parser::new();
-
-            thread::spawn(move || loop {
-                let mut buffer = [0u8; 1024];
-                match file_out.read(&mut buffer) {
-                    Ok(n) if n > 0 => {
-                        for b in &buffer[..n] {
-                            parser.advance(&mut handler, *b);
-                        }
-                    }
-                    _ => {
-                        break;
-                    }
-                }
-            });
-
-            let inner = PtyInner {
-                handle,
-                pipe_in: pipe_pty_in,
-                pipe_out: pipe_pty_out,
-            };
-
-            thread::spawn(move || loop {
-                match rx.recv() {
-                    Ok(Action::Write(b)) => {
-                        file_in.write(&[b]).unwrap();
-                    }
-                    Ok(Action::Resize(x, y)) => {
-                        ResizePseudoConsole(inner.handle, COORD { X: x, Y: y });
-                    }
-                    Err(RecvError) => {
-                        break;
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        printed: String,
+        apcs: Vec<Vec<u8>>,
+    }
+
+    impl Perform for Recorder {
+        fn print(&mut self, c: char) {
+            self.printed.push(c);
+        }
+    }
+
+    impl Handler for Recorder {
+        fn apc_dispatch(&mut self, data: &[u8]) {
+            self.apcs.push(data.to_vec());
+        }
+    }
+
+    #[test]
+    fn apc_strings_are_taken_out_of_the_output() {
+        let mut parser = Parser::new();
+        let mut recorder = Recorder::default();
+        for &b in b"a\x1b_Gi=1;AAAA\x1b\\b\x1b_x\x1b[31mc\x1b7d" {
+            parser.advance(&mut recorder, b);
+        }
+        assert_eq!(recorder.printed, "abcd");
+        assert_eq!(recorder.apcs, vec![b"Gi=1;AAAA".to_vec(), b"x".to_vec()]);
+    }
+
+    // Sends what the shell prints, with line feeds, to the test.
+    #[cfg(unix)]
+    struct Output(std::sync::mpsc::Sender<char>);
+
+    #[cfg(unix)]
+    impl Perform for Output {
+        fn print(&mut self, c: char) {
+            let _ = self.0.send(c);
+        }
+        fn execute(&mut self, byte: u8) {
+            if byte == b'\n' {
+                let _ = self.0.send('\n');
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    impl Handler for Output {
+        fn apc_dispatch(&mut self, _data: &[u8]) {}
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawned_shell_gets_the_environment_and_window_size() {
+        use std::time::Duration;
+
+        let (tx, rx) = channel();
+        let config = Config {
+            shell: "sh",
+            cols: 80,
+            rows: 24,
+            cwd: "/",
+        };
+        let pty = Pty::spawn(&config, Output(tx)).unwrap();
+        let run = |command: &str| {
+            for b in command.bytes() {
+                pty.write(b).unwrap();
+            }
+        };
+        // Waits for the output of a command. The expansions keep the echoed
+        // command line from matching.
+        let expect = |text: &str| {
+            let mut output = String::new();
+            while let Ok(c) = rx.recv_timeout(Duration::from_secs(10)) {
+                output.push(c);
+                if output.contains(text) {
+                    return;
                 }
-            });
+            }
+            panic!("{:?} not found in {:?}", text, output);
+        };
+        run("echo \"<$TERM> <$(pwd)>\"\n");
+        expect("<xterm-256color> </>");
+        run("echo \"<$(stty size)>\"\n");
+        expect("<24 80>");
+        pty.resize(100, 30).unwrap();
+        run("echo \"<$(stty size)>\"\n");
+        expect("<30 100>");
+        run("exit\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spawned_shell_starts_with_default_signal_handling() {
+        use std::time::Duration;
 
-            Ok(Pty { tx })
+        let (tx, rx) = channel();
+        let config = Config {
+            shell: "sh",
+            cols: 80,
+            rows: 24,
+            cwd: "/",
+        };
+        let pty = Pty::spawn(&config, Output(tx)).unwrap();
+        // `yes` complains about the broken pipe only if SIGPIPE is ignored.
+        let commands = "echo \"<$( (yes | head -n 1) 2>&1 | tr '\\n' ' ')>\"\n\
+                        grep SigBlk /proc/self/status | tr -d '\\t'\n\
+                        exit\n";
+        for b in commands.bytes() {
+            pty.write(b).unwrap();
         }
+        // The output ends when the shell exits.
+        let output: String =
+            std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(10)).ok()).collect();
+        assert!(output.contains("<y >"), "{:?}", output);
+        assert!(output.contains("SigBlk:0000000000000000"), "{:?}", output);
     }
 }
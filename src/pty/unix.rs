@@ -0,0 +1,255 @@
+use std::env;
+use std::error::Error;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::ptr::{null, null_mut};
+use std::sync::mpsc::{Receiver, RecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libc::{c_int, pid_t, winsize};
+
+use super::{Action, Config, Handler, Parser};
+
+// The shell, reaped by whichever thread first finds it gone: the reader at
+// the end of the output, or the writer when the terminal closes.
+struct Child {
+    pid: pid_t,
+    reaped: Mutex<bool>,
+}
+
+impl Child {
+    // Waits for the shell to exit, hanging it up first if `hang_up`.
+    fn reap(&self, hang_up: bool) {
+        let mut reaped = self.reaped.lock().unwrap();
+        if *reaped {
+            return;
+        }
+        unsafe {
+            if hang_up {
+                libc::kill(self.pid, libc::SIGHUP);
+            }
+            libc::waitpid(self.pid, null_mut(), 0);
+        }
+        *reaped = true;
+    }
+}
+
+struct PtyInner {
+    master: File,
+    child: Arc<Child>,
+}
+
+impl Drop for PtyInner {
+    fn drop(&mut self) {
+        self.child.reap(true);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Process {
+    master: Arc<File>,
+}
+
+impl Process {
+    // The working directory of the foreground process group, i.e. the shell
+    // or the program it is running.
+    #[cfg(target_os = "linux")]
+    pub fn foreground_cwd(&self) -> Option<PathBuf> {
+        let pgid = unsafe { libc::tcgetpgrp(self.master.as_raw_fd()) };
+        if pgid < 0 {
+            return None;
+        }
+        fs::read_link(format!("/proc/{}/cwd", pgid)).ok()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn foreground_cwd(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+fn window_size(cols: u16, rows: u16) -> winsize {
+    winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+// Keeps the fd from leaking into programs that we run later, such as the
+// browser or the notifier, which would hold the pty open after we exit.
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Finds the program the way execvp would, since execve does not search PATH.
+fn find_program(program: &str) -> PathBuf {
+    if program.contains('/') {
+        return PathBuf::from(program);
+    }
+    env::var_os("PATH")
+        .and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(program))
+                .find(|path| path.is_file())
+        })
+        .unwrap_or_else(|| PathBuf::from(program))
+}
+
+fn c_string(s: &OsStr) -> Result<CString, Box<dyn Error>> {
+    Ok(CString::new(s.as_bytes())?)
+}
+
+fn set_window_size(fd: RawFd, size: &winsize) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, size as *const winsize) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn spawn<H: Handler + Send + 'static>(
+    config: &Config,
+    mut handler: H,
+    rx: Receiver<Action>,
+) -> Result<Process, Box<dyn Error>> {
+    // Everything the child needs is allocated before fork: only
+    // async-signal-safe calls may run between fork and exec.
+    let shell = CString::new(config.shell)?;
+    let program = c_string(find_program(config.shell).as_os_str())?;
+    let cwd = CString::new(config.cwd)?;
+    let argv = [shell.as_ptr(), null()];
+    let mut env = vec![CString::new("TERM=xterm-256color")?];
+    for (key, value) in env::vars_os().filter(|(key, _)| key != "TERM") {
+        let mut pair = key.as_bytes().to_vec();
+        pair.push(b'=');
+        pair.extend_from_slice(value.as_bytes());
+        // Variables with a NUL byte cannot be passed on.
+        if let Ok(pair) = CString::new(pair) {
+            env.push(pair);
+        }
+    }
+    let envp: Vec<_> = env
+        .iter()
+        .map(|pair| pair.as_ptr())
+        .chain(Some(null()))
+        .collect();
+    let mut no_signals = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe { libc::sigemptyset(&mut no_signals) };
+
+    let mut master: c_int = -1;
+    let mut slave: c_int = -1;
+    let size = window_size(config.cols as u16, config.rows as u16);
+    if unsafe { libc::openpty(&mut master, &mut slave, null_mut(), null(), &size) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if let Err(err) = set_cloexec(master).and_then(|()| set_cloexec(slave)) {
+        unsafe {
+            libc::close(master);
+            libc::close(slave);
+        }
+        return Err(err.into());
+    }
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(master);
+            libc::close(slave);
+        }
+        return Err(err.into());
+    }
+
+    if pid == 0 {
+        unsafe {
+            // Start a new session so that the slave becomes our controlling terminal.
+            libc::setsid();
+            libc::ioctl(slave, libc::TIOCSCTTY as _, 0);
+            libc::dup2(slave, 0);
+            libc::dup2(slave, 1);
+            libc::dup2(slave, 2);
+            if slave > 2 {
+                libc::close(slave);
+            }
+            libc::close(master);
+            libc::chdir(cwd.as_ptr());
+            // Ignored signals and the signal mask survive exec. Rust ignores
+            // SIGPIPE, which the shell's pipelines rely on.
+            for signal in 1..32 {
+                if signal != libc::SIGKILL && signal != libc::SIGSTOP {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+            libc::sigprocmask(libc::SIG_SETMASK, &no_signals, null_mut());
+            libc::execve(program.as_ptr(), argv.as_ptr(), envp.as_ptr());
+            libc::_exit(127);
+        }
+    }
+
+    unsafe { libc::close(slave) };
+
+    let mut file_in = unsafe { File::from_raw_fd(master) };
+    let mut file_out = file_in.try_clone()?;
+
+    let mut parser = Parser::new();
+    let child = Arc::new(Child {
+        pid,
+        reaped: Mutex::new(false),
+    });
+
+    let reader_child = child.clone();
+    thread::spawn(move || {
+        loop {
+            let mut buffer = [0u8; 1024];
+            match file_out.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    for b in &buffer[..n] {
+                        parser.advance(&mut handler, *b);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                _ => {
+                    // Linux reports EIO once the child has exited and the slave is closed.
+                    break;
+                }
+            }
+        }
+        // Keeps the shell from lingering as a zombie until the next key press.
+        reader_child.reap(false);
+    });
+
+    let process = Process {
+        master: Arc::new(file_in.try_clone()?),
+    };
+    let inner = PtyInner {
+        master: file_in.try_clone()?,
+        child,
+    };
+
+    // Writing fails once the child has exited, which ends the thread.
+    thread::spawn(move || loop {
+        let result = match rx.recv() {
+            Ok(Action::Write(b)) => file_in.write_all(&[b]),
+            Ok(Action::Resize(x, y)) => {
+                let size = window_size(x as u16, y as u16);
+                set_window_size(inner.master.as_raw_fd(), &size)
+            }
+            Err(RecvError) => break,
+        };
+        if result.is_err() {
+            break;
+        }
+    });
+
+    Ok(process)
+}
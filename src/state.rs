@@ -1,7 +1,191 @@
+use crate::charset::Charset;
+use crate::clipboard;
+use crate::graphics::{kitty, Placement, MAX_IMAGE_BYTES, MAX_IMAGE_SCREENS};
+use crate::mode::{Mode, Modes};
+use crate::notify::Notification;
+use crate::palette::Palette;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long rendering waits for an application that turned on synchronized
+// output, in case it never turns it off again.
+const SYNCHRONIZED_OUTPUT_TIMEOUT: Duration = Duration::from_millis(150);
+
+// How long the screen flashes on BEL.
+const VISUAL_BELL_DURATION: Duration = Duration::from_millis(100);
+
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct State {
     pub cursor: Position,
-    pub lines: Vec<Vec<char>>,
+    pub cols: usize,
+    pub rows: usize,
+    pub lines: Vec<Line>,
+    // The screen buffer that is not shown: the primary screen while the
+    // alternate screen is active, and vice versa.
+    pub inactive_lines: Vec<Line>,
+    pub alternate_screen: bool,
+    // DECSC slots, one for each screen buffer (primary, alternate).
+    pub saved_cursors: [SavedCursor; 2],
+    // Lines that scrolled off the top of the screen, oldest first.
+    pub scrollback: VecDeque<Line>,
+    pub scrollback_limit: usize,
+    // How many lines the viewport is scrolled back into the history (0 = bottom).
+    pub viewport_offset: usize,
+    // The attributes given to newly printed characters (SGR).
+    pub pen: Cell,
+    // G0-G3 and the one invoked into GL by SI/SO.
+    pub charsets: [Charset; 4],
+    pub active_charset: usize,
+    // The charset used for the next printed character only (SS2/SS3).
+    pub single_shift: Option<usize>,
+    // Horizontal tab stops, one flag per column.
+    pub tabs: Vec<bool>,
+    // The scrolling region, `scroll_top..scroll_bottom`.
+    pub scroll_top: usize,
+    pub scroll_bottom: usize,
+    pub modes: Modes,
+    // When synchronized output (mode 2026) was turned on.
+    pub synchronized_since: Option<Instant>,
+    // DECSCUSR: 0-2 block, 3-4 underline, 5-6 bar. Odd values blink.
+    pub cursor_style: u8,
+    // Set by OSC 0/1/2, and saved by XTWINOPS 22 / restored by 23.
+    pub title: String,
+    pub icon_name: String,
+    pub title_stack: Vec<String>,
+    pub icon_name_stack: Vec<String>,
+    // The working directory reported by OSC 7.
+    pub cwd: Option<String>,
+    // The hyperlinks that cells refer to, by the number kept in the cells,
+    // and the number of each link so that repeated OSC 8s share one.
+    pub hyperlinks: HashMap<usize, Hyperlink>,
+    pub hyperlink_numbers: HashMap<Hyperlink, usize>,
+    pub next_hyperlink: usize,
+    // Links that no cell refers to any more are dropped once there are this
+    // many.
+    pub hyperlink_limit: usize,
+    pub palette: Palette,
+    // The size of a cell in physical pixels, and the window's DPI factor.
+    pub cell_size: (f64, f64),
+    pub dpi_factor: f64,
+    // Images on the grid, oldest first.
+    pub images: Vec<Placement>,
+    pub next_image_id: u64,
+    // Images transmitted with the kitty graphics protocol.
+    pub kitty_images: kitty::Storage,
+    // The cell under the mouse pointer, in viewport coordinates.
+    pub mouse: Option<Position>,
+    // Selected rows of the primary screen, counted from the oldest line in
+    // the scrollback.
+    pub selection: Option<RangeInclusive<usize>>,
+    // Bytes to be sent back to the application, e.g. replies to reports.
+    pub responses: Vec<u8>,
+    // OSC 52 requests waiting to be carried out by the main loop.
+    pub clipboard_requests: Vec<clipboard::Request>,
+    // Whether BEL was received since the main loop last rang the bell.
+    pub bell: bool,
+    // The end of the visual bell. A bell during the flash extends it.
+    pub flash_until: Option<Instant>,
+    // OSC 9 and 777 notifications waiting to be shown.
+    pub notifications: Vec<Notification>,
+    // Set after a character is printed in the last column. The wrap itself is
+    // deferred until the next printable character arrives.
+    pub wrap_pending: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attributes,
+    // A key of `State::hyperlinks` (OSC 8).
+    pub hyperlink: Option<usize>,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            fg: Color::Default,
+            bg: Color::Default,
+            attrs: Attributes::default(),
+            hyperlink: None,
+        }
+    }
+}
+
+// A row of cells and the shell integration marks it carries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub cells: Vec<Cell>,
+    pub marks: Marks,
+}
+
+impl Line {
+    pub fn new(cell: Cell, cols: usize) -> Self {
+        Self {
+            cells: vec![cell; cols],
+            marks: Marks::default(),
+        }
+    }
+}
+
+impl Deref for Line {
+    type Target = Vec<Cell>;
+
+    fn deref(&self) -> &Vec<Cell> {
+        &self.cells
+    }
+}
+
+impl DerefMut for Line {
+    fn deref_mut(&mut self) -> &mut Vec<Cell> {
+        &mut self.cells
+    }
+}
+
+// The semantic prompt marks of OSC 133 (FinalTerm).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Marks {
+    // A prompt starts on this line (`A`).
+    pub prompt: bool,
+    // The user's command starts on this line (`B`).
+    pub command: bool,
+    // The output of the command starts on this line (`C`).
+    pub output: bool,
+    // Set on the prompt line once its command finished (`D`).
+    pub exit_status: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Hyperlink {
+    // The `id=` parameter, which joins cells that are not adjacent.
+    pub id: Option<String>,
+    pub uri: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Color {
+    #[default]
+    Default,
+    // 0-15 are the ANSI colours, 16-255 the xterm 256-colour extension.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attributes {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub inverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -10,11 +194,848 @@ pub struct Position {
     pub y: usize,
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedCursor {
+    pub position: Position,
+    pub pen: Cell,
+    pub charsets: [Charset; 4],
+    pub active_charset: usize,
+    pub origin_mode: bool,
+    pub wrap_pending: bool,
+}
+
+const MIN_HYPERLINK_LIMIT: usize = 256;
+
+fn default_tabs(cols: usize) -> Vec<bool> {
+    (0..cols).map(|x| x % 8 == 0).collect()
+}
+
 impl State {
-    pub fn new() -> Self {
+    pub fn new(cols: usize, rows: usize, scrollback_limit: usize) -> Self {
         Self {
             cursor: Position { x: 0, y: 0 },
-            lines: vec![],
+            cols,
+            rows,
+            lines: vec![Line::new(Cell::default(), cols); rows],
+            inactive_lines: vec![Line::new(Cell::default(), cols); rows],
+            alternate_screen: false,
+            saved_cursors: Default::default(),
+            scrollback: VecDeque::new(),
+            scrollback_limit,
+            viewport_offset: 0,
+            pen: Cell::default(),
+            charsets: Default::default(),
+            active_charset: 0,
+            single_shift: None,
+            tabs: default_tabs(cols),
+            scroll_top: 0,
+            scroll_bottom: rows,
+            modes: Modes::default(),
+            synchronized_since: None,
+            cursor_style: 0,
+            title: String::new(),
+            icon_name: String::new(),
+            title_stack: vec![],
+            icon_name_stack: vec![],
+            cwd: None,
+            hyperlinks: HashMap::new(),
+            hyperlink_numbers: HashMap::new(),
+            next_hyperlink: 0,
+            hyperlink_limit: MIN_HYPERLINK_LIMIT,
+            palette: Palette::default(),
+            cell_size: (10.0, 20.0),
+            dpi_factor: 1.0,
+            images: vec![],
+            next_image_id: 0,
+            kitty_images: kitty::Storage::default(),
+            mouse: None,
+            selection: None,
+            responses: vec![],
+            clipboard_requests: vec![],
+            bell: false,
+            flash_until: None,
+            notifications: vec![],
+            wrap_pending: false,
+        }
+    }
+
+    pub fn respond(&mut self, response: &str) {
+        self.responses.extend_from_slice(response.as_bytes());
+    }
+
+    pub fn print(&mut self, c: char) {
+        if self.wrap_pending {
+            self.carriage_return();
+            self.linefeed();
+        }
+        let charset = self.single_shift.take().unwrap_or(self.active_charset);
+        let c = self.charsets[charset].translate(c);
+        if self.modes.get(Mode::Insert) {
+            self.insert_chars(1);
+        }
+        self.lines[self.cursor.y][self.cursor.x] = Cell { c, ..self.pen };
+        if self.cursor.x + 1 < self.cols {
+            self.cursor.x += 1;
+        } else if self.modes.get(Mode::Autowrap) {
+            self.wrap_pending = true;
+        }
+    }
+
+    pub fn linefeed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.y + 1 == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.y + 1 < self.rows {
+            self.cursor.y += 1;
+        }
+    }
+
+    // RI: Reverse Index
+    pub fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.y == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.y > 0 {
+            self.cursor.y -= 1;
         }
     }
+
+    pub fn carriage_return(&mut self) {
+        self.wrap_pending = false;
+        self.cursor.x = 0;
+    }
+
+    pub fn backspace(&mut self) {
+        self.wrap_pending = false;
+        self.cursor.x = self.cursor.x.saturating_sub(1);
+    }
+
+    pub fn move_to(&mut self, x: usize, y: usize) {
+        self.wrap_pending = false;
+        self.cursor.x = x.min(self.cols - 1);
+        self.cursor.y = y.min(self.rows - 1);
+    }
+
+    // Moves the cursor to an addressed position, which is relative to the
+    // scrolling region in origin mode.
+    pub fn goto(&mut self, x: usize, y: usize) {
+        let y = if self.modes.get(Mode::Origin) {
+            (self.scroll_top + y).min(self.scroll_bottom - 1)
+        } else {
+            y
+        };
+        self.move_to(x, y);
+    }
+
+    // The cursor position as reported to the application, relative to the
+    // scrolling region in origin mode.
+    pub fn reported_cursor(&self) -> (usize, usize) {
+        let Position { x, y } = self.cursor;
+        if self.modes.get(Mode::Origin) {
+            (x, y.saturating_sub(self.scroll_top))
+        } else {
+            (x, y)
+        }
+    }
+
+    pub fn goto_row(&mut self, y: usize) {
+        let x = self.cursor.x;
+        self.goto(x, y);
+    }
+
+    pub fn goto_column(&mut self, x: usize) {
+        let y = self.cursor.y;
+        self.move_to(x, y);
+    }
+
+    // Moves the cursor up, stopping at the top margin if it starts inside the
+    // scrolling region.
+    pub fn move_up(&mut self, n: usize) {
+        let Position { x, y } = self.cursor;
+        let top = if y >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        };
+        self.move_to(x, y.saturating_sub(n).max(top));
+    }
+
+    // Moves the cursor down, stopping at the bottom margin if it starts inside
+    // the scrolling region.
+    pub fn move_down(&mut self, n: usize) {
+        let Position { x, y } = self.cursor;
+        let bottom = if y < self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows
+        };
+        self.move_to(x, y.saturating_add(n).min(bottom - 1));
+    }
+
+    pub fn move_left(&mut self, n: usize) {
+        let Position { x, y } = self.cursor;
+        self.move_to(x.saturating_sub(n), y);
+    }
+
+    pub fn move_right(&mut self, n: usize) {
+        let Position { x, y } = self.cursor;
+        self.move_to(x.saturating_add(n), y);
+    }
+
+    // DECSTBM: Set Top and Bottom Margins
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let bottom = bottom.min(self.rows);
+        if top + 1 >= bottom {
+            return;
+        }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+        self.goto(0, 0);
+    }
+
+    // Scrolls the scrolling region up by `n` lines. Lines leaving the top only
+    // enter the scrollback when the region covers the whole screen.
+    pub fn scroll_up(&mut self, n: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let n = n.min(bottom - top);
+        let blank = Line::new(self.blank(), self.cols);
+        let scrolled: Vec<_> = self
+            .lines
+            .splice(top..top + n, std::iter::empty())
+            .collect();
+        self.lines
+            .splice(bottom - n..bottom - n, std::iter::repeat_n(blank, n));
+        if top == 0 && bottom == self.rows && !self.alternate_screen && self.scrollback_limit > 0 {
+            for line in scrolled {
+                self.push_scrollback(line);
+            }
+        } else {
+            self.shift_images(top, bottom, -(n as i64));
+            self.drop_unused_hyperlinks();
+        }
+    }
+
+    // Scrolls the scrolling region down by `n` lines.
+    pub fn scroll_down(&mut self, n: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let n = n.min(bottom - top);
+        let blank = Line::new(self.blank(), self.cols);
+        self.lines[top..bottom].rotate_right(n);
+        for line in &mut self.lines[top..top + n] {
+            *line = blank.clone();
+        }
+        self.shift_images(top, bottom, n as i64);
+        self.drop_unused_hyperlinks();
+    }
+
+    // Moves the images anchored in the screen rows `[top, bottom)` by
+    // `delta` rows, dropping those that leave the range.
+    fn shift_images(&mut self, top: usize, bottom: usize, delta: i64) {
+        let offset = self.scrollback.len();
+        let (top, bottom) = ((offset + top) as i64, (offset + bottom) as i64);
+        let alternate = self.alternate_screen;
+        self.images.retain_mut(|image| {
+            let row = image.row as i64;
+            if image.alternate != alternate || row < top || row >= bottom {
+                return true;
+            }
+            image.row = (row + delta) as usize;
+            (top..bottom).contains(&(row + delta))
+        });
+    }
+
+    // An empty cell painted with the current background colour (BCE).
+    pub fn blank(&self) -> Cell {
+        Cell {
+            bg: self.pen.bg,
+            ..Cell::default()
+        }
+    }
+
+    // Blanks the columns `[from, to)` of line `y`.
+    pub fn erase(&mut self, y: usize, from: usize, to: usize) {
+        let blank = self.blank();
+        let to = to.min(self.cols);
+        for cell in &mut self.lines[y][from.min(to)..to] {
+            *cell = blank;
+        }
+        self.drop_unused_hyperlinks();
+    }
+
+    // Blanks line `y` and drops its marks, so that jumps skip it.
+    fn erase_line(&mut self, y: usize) {
+        self.erase(y, 0, self.cols);
+        self.lines[y].marks = Marks::default();
+    }
+
+    // ED: Erase in Display. The cursor line keeps its marks, as shells
+    // clear from the cursor when they redraw the prompt they just marked.
+    pub fn erase_in_display(&mut self, mode: i64) {
+        self.wrap_pending = false;
+        let Position { x, y } = self.cursor;
+        match mode {
+            0 => {
+                self.erase(y, x, self.cols);
+                for y in y + 1..self.rows {
+                    self.erase_line(y);
+                }
+            }
+            1 => {
+                for y in 0..y {
+                    self.erase_line(y);
+                }
+                self.erase(y, 0, x + 1);
+            }
+            2 => self.clear_screen(),
+            3 => {
+                // Rows are counted from the oldest line in the scrollback on
+                // both screens.
+                let scrollback = self.scrollback.len();
+                self.images.retain(|image| image.row >= scrollback);
+                for image in &mut self.images {
+                    image.row -= scrollback;
+                }
+                self.selection = match self.selection.take() {
+                    Some(rows) if *rows.end() >= scrollback => {
+                        Some(rows.start().saturating_sub(scrollback)..=rows.end() - scrollback)
+                    }
+                    _ => None,
+                };
+                self.scrollback.clear();
+                self.viewport_offset = 0;
+                self.prune_hyperlinks();
+            }
+            _ => {}
+        }
+    }
+
+    // EL: Erase in Line
+    pub fn erase_in_line(&mut self, mode: i64) {
+        self.wrap_pending = false;
+        let Position { x, y } = self.cursor;
+        match mode {
+            0 => self.erase(y, x, self.cols),
+            1 => self.erase(y, 0, x + 1),
+            2 => self.erase(y, 0, self.cols),
+            _ => {}
+        }
+    }
+
+    // ECH: Erase Character
+    pub fn erase_chars(&mut self, n: usize) {
+        self.wrap_pending = false;
+        let Position { x, y } = self.cursor;
+        self.erase(y, x, x + n);
+    }
+
+    fn push_scrollback(&mut self, line: Line) {
+        let has_links = |line: &Line| line.iter().any(|cell| cell.hyperlink.is_some());
+        let dropped = if self.scrollback_limit == 0 {
+            Some(line)
+        } else {
+            let dropped = if self.scrollback.len() == self.scrollback_limit {
+                self.images.retain(|image| image.alternate || image.row > 0);
+                for image in self.images.iter_mut().filter(|image| !image.alternate) {
+                    image.row -= 1;
+                }
+                // Selected rows move up with the lines they select.
+                self.selection = match self.selection.take() {
+                    Some(rows) if *rows.start() > 0 => Some(rows.start() - 1..=rows.end() - 1),
+                    _ => None,
+                };
+                self.scrollback.pop_front()
+            } else {
+                None
+            };
+            self.scrollback.push_back(line);
+            // Keep the viewport on the same content while output arrives,
+            // until that content is dropped from the history.
+            if self.viewport_offset > 0 {
+                self.viewport_offset = (self.viewport_offset + 1).min(self.scrollback.len());
+            }
+            dropped
+        };
+        if dropped.is_some_and(|line| has_links(&line)) {
+            self.drop_unused_hyperlinks();
+        }
+    }
+
+    pub fn scroll_viewport(&mut self, delta: i64) {
+        if self.alternate_screen {
+            return;
+        }
+        let offset = self.viewport_offset as i64 + delta;
+        self.viewport_offset = offset.max(0).min(self.scrollback.len() as i64) as usize;
+    }
+
+    pub fn visible_lines(&self) -> impl Iterator<Item = &Line> {
+        let start = self.scrollback.len() - self.viewport_offset;
+        self.scrollback
+            .iter()
+            .skip(start)
+            .chain(self.lines.iter())
+            .take(self.rows)
+    }
+
+    // ICH: Insert Character
+    pub fn insert_chars(&mut self, n: usize) {
+        self.wrap_pending = false;
+        let Position { x, y } = self.cursor;
+        let n = n.min(self.cols - x);
+        let blank = self.blank();
+        let line = &mut self.lines[y];
+        line[x..].rotate_right(n);
+        for cell in &mut line[x..x + n] {
+            *cell = blank;
+        }
+        self.drop_unused_hyperlinks();
+    }
+
+    // DCH: Delete Character
+    pub fn delete_chars(&mut self, n: usize) {
+        self.wrap_pending = false;
+        let Position { x, y } = self.cursor;
+        let n = n.min(self.cols - x);
+        let blank = self.blank();
+        let line = &mut self.lines[y];
+        line[x..].rotate_left(n);
+        for cell in &mut line[self.cols - n..] {
+            *cell = blank;
+        }
+        self.drop_unused_hyperlinks();
+    }
+
+    // IL: Insert Line
+    pub fn insert_lines(&mut self, n: usize) {
+        let y = self.cursor.y;
+        if y < self.scroll_top || y >= self.scroll_bottom {
+            return;
+        }
+        let n = n.min(self.scroll_bottom - y);
+        let blank = self.blank();
+        self.lines[y..self.scroll_bottom].rotate_right(n);
+        for line in &mut self.lines[y..y + n] {
+            *line = Line::new(blank, self.cols);
+        }
+        self.drop_unused_hyperlinks();
+        self.carriage_return();
+    }
+
+    // DL: Delete Line
+    pub fn delete_lines(&mut self, n: usize) {
+        let y = self.cursor.y;
+        if y < self.scroll_top || y >= self.scroll_bottom {
+            return;
+        }
+        let n = n.min(self.scroll_bottom - y);
+        let blank = self.blank();
+        self.lines[y..self.scroll_bottom].rotate_left(n);
+        for line in &mut self.lines[self.scroll_bottom - n..self.scroll_bottom] {
+            *line = Line::new(blank, self.cols);
+        }
+        self.drop_unused_hyperlinks();
+        self.carriage_return();
+    }
+
+    // DECSC: Save Cursor
+    pub fn save_cursor(&mut self) {
+        self.saved_cursors[self.alternate_screen as usize] = SavedCursor {
+            position: self.cursor.clone(),
+            pen: self.pen,
+            charsets: self.charsets,
+            active_charset: self.active_charset,
+            origin_mode: self.modes.get(Mode::Origin),
+            wrap_pending: self.wrap_pending,
+        };
+    }
+
+    // DECRC: Restore Cursor
+    pub fn restore_cursor(&mut self) {
+        let saved = self.saved_cursors[self.alternate_screen as usize].clone();
+        self.move_to(saved.position.x, saved.position.y);
+        self.pen = saved.pen;
+        self.charsets = saved.charsets;
+        self.active_charset = saved.active_charset;
+        self.modes.set(Mode::Origin, saved.origin_mode);
+        self.wrap_pending = saved.wrap_pending;
+    }
+
+    // Switches between the primary and the alternate screen buffer.
+    pub fn switch_screen(&mut self, alternate: bool) {
+        if self.alternate_screen == alternate {
+            return;
+        }
+        std::mem::swap(&mut self.lines, &mut self.inactive_lines);
+        self.alternate_screen = alternate;
+        self.selection = None;
+        if !alternate {
+            self.images.retain(|image| !image.alternate);
+        }
+        self.viewport_offset = 0;
+    }
+
+    pub fn clear_screen(&mut self) {
+        for y in 0..self.rows {
+            self.erase_line(y);
+        }
+        let (top, alternate) = (self.scrollback.len(), self.alternate_screen);
+        self.images
+            .retain(|image| image.alternate != alternate || image.row < top);
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        if (cols, rows) == (self.cols, self.rows) || cols == 0 || rows == 0 {
+            return;
+        }
+
+        for line in self.lines.iter_mut().chain(self.inactive_lines.iter_mut()) {
+            line.resize(cols, Cell::default());
+        }
+        // Keep the cursor line on screen by pushing lines off the top.
+        let excess = (self.cursor.y + 1).saturating_sub(rows);
+        let scrolled: Vec<_> = self.lines.drain(..excess).collect();
+        if !self.alternate_screen {
+            for line in scrolled {
+                self.push_scrollback(line);
+            }
+        }
+        self.cursor.y -= excess;
+        if self.alternate_screen {
+            // Likewise for the primary screen and the cursor saved with it,
+            // which comes back when the application exits.
+            let saved = &mut self.saved_cursors[0].position;
+            let excess = (saved.y + 1).saturating_sub(rows);
+            saved.y -= excess;
+            let scrolled: Vec<_> = self.inactive_lines.drain(..excess).collect();
+            let before = self.scrollback.len();
+            for line in scrolled {
+                self.push_scrollback(line);
+            }
+            // The alternate screen stays below the scrollback.
+            let grown = self.scrollback.len() - before;
+            for image in self.images.iter_mut().filter(|image| image.alternate) {
+                image.row += grown;
+            }
+        }
+        for saved in &mut self.saved_cursors {
+            saved.position.x = saved.position.x.min(cols - 1);
+            saved.position.y = saved.position.y.min(rows - 1);
+        }
+        self.lines.resize(rows, Line::new(Cell::default(), cols));
+        self.inactive_lines
+            .resize(rows, Line::new(Cell::default(), cols));
+
+        self.cols = cols;
+        self.rows = rows;
+        self.tabs = default_tabs(cols);
+        self.scroll_top = 0;
+        self.scroll_bottom = rows;
+        let Position { x, y } = self.cursor;
+        self.move_to(x, y);
+    }
+
+    // HT: Horizontal Tab, CHT: Cursor Forward Tabulation
+    pub fn tab_forward(&mut self, n: usize) {
+        let mut x = self.cursor.x;
+        for _ in 0..n {
+            x = (x + 1..self.cols)
+                .find(|&x| self.tabs[x])
+                .unwrap_or(self.cols - 1);
+        }
+        self.goto_column(x);
+    }
+
+    // CBT: Cursor Backward Tabulation
+    pub fn tab_backward(&mut self, n: usize) {
+        let mut x = self.cursor.x;
+        for _ in 0..n {
+            x = (0..x).rev().find(|&x| self.tabs[x]).unwrap_or(0);
+        }
+        self.goto_column(x);
+    }
+
+    // HTS: Horizontal Tab Set
+    pub fn set_tab(&mut self) {
+        self.tabs[self.cursor.x] = true;
+    }
+
+    // TBC: Tab Clear
+    pub fn clear_tabs(&mut self, mode: i64) {
+        match mode {
+            0 => self.tabs[self.cursor.x] = false,
+            3 => self.tabs.iter_mut().for_each(|tab| *tab = false),
+            _ => {}
+        }
+    }
+
+    // OSC 8: starts a hyperlink for the following characters, or ends it when
+    // `uri` is empty.
+    pub fn set_hyperlink(&mut self, id: Option<String>, uri: String) {
+        if uri.is_empty() {
+            self.pen.hyperlink = None;
+            return;
+        }
+        let link = Hyperlink { id, uri };
+        let number = match self.hyperlink_numbers.get(&link) {
+            Some(&number) => number,
+            None => {
+                let number = self.next_hyperlink;
+                self.next_hyperlink += 1;
+                self.hyperlinks.insert(number, link.clone());
+                self.hyperlink_numbers.insert(link, number);
+                number
+            }
+        };
+        self.pen.hyperlink = Some(number);
+    }
+
+    // Called wherever cells are overwritten or lines dropped. Looking for
+    // unused links means going through every cell, so it is done only once
+    // their number has doubled.
+    fn drop_unused_hyperlinks(&mut self) {
+        if self.hyperlinks.len() >= self.hyperlink_limit {
+            self.prune_hyperlinks();
+        }
+    }
+
+    // Drops the hyperlinks that no cell refers to.
+    fn prune_hyperlinks(&mut self) {
+        let used: HashSet<usize> = self
+            .scrollback
+            .iter()
+            .chain(&self.lines)
+            .chain(&self.inactive_lines)
+            .flat_map(|line| line.iter().filter_map(|cell| cell.hyperlink))
+            .chain(self.pen.hyperlink)
+            .collect();
+        self.hyperlinks.retain(|number, _| used.contains(number));
+        self.hyperlink_numbers
+            .retain(|_, number| used.contains(number));
+        self.hyperlink_limit = (self.hyperlinks.len() * 2).max(MIN_HYPERLINK_LIMIT);
+    }
+
+    // Moves the mouse pointer to a position in logical pixels.
+    pub fn move_mouse(&mut self, x: f64, y: f64) {
+        let (width, height) = self.cell_size;
+        let x = (x * self.dpi_factor / width).max(0.0) as usize;
+        let y = (y * self.dpi_factor / height).max(0.0) as usize;
+        self.mouse = Some(Position {
+            x: x.min(self.cols - 1),
+            y: y.min(self.rows - 1),
+        });
+    }
+
+    // The index of the hyperlink under the mouse pointer.
+    pub fn hovered_hyperlink(&self) -> Option<usize> {
+        let Position { x, y } = self.mouse.clone()?;
+        self.visible_lines().nth(y)?.get(x)?.hyperlink
+    }
+
+    // Every line of the scrollback and the screen, oldest first.
+    fn history(&self) -> impl Iterator<Item = &Line> {
+        self.scrollback.iter().chain(self.lines.iter())
+    }
+
+    // The row of the cursor, counted from the oldest line in the scrollback.
+    pub fn cursor_row(&self) -> usize {
+        self.scrollback.len() + self.cursor.y
+    }
+
+    // OSC 133: marks the cursor line with `A`, `B` or `C`.
+    pub fn mark_line(&mut self, mark: char) {
+        let marks = &mut self.lines[self.cursor.y].marks;
+        match mark {
+            'A' => marks.prompt = true,
+            'B' => marks.command = true,
+            'C' => marks.output = true,
+            _ => {}
+        }
+    }
+
+    // OSC 133 ; D: records the exit status on the line of the latest prompt.
+    pub fn finish_command(&mut self, exit_status: Option<i32>) {
+        let scrollback = self.scrollback.len();
+        let row = self
+            .history()
+            .take(self.cursor_row() + 1)
+            .enumerate()
+            .filter(|(_, line)| line.marks.prompt)
+            .map(|(row, _)| row)
+            .last();
+        let line = match row {
+            Some(row) if row < scrollback => &mut self.scrollback[row],
+            Some(row) => &mut self.lines[row - scrollback],
+            None => return,
+        };
+        line.marks.exit_status = exit_status;
+    }
+
+    // Scrolls the viewport so that the previous (`direction < 0`) or next
+    // prompt is at the top.
+    pub fn jump_to_prompt(&mut self, direction: i64) {
+        if self.alternate_screen {
+            return;
+        }
+        let top = self.scrollback.len() - self.viewport_offset;
+        let row = {
+            let mut prompts = self
+                .history()
+                .enumerate()
+                .filter(|(_, line)| line.marks.prompt)
+                .map(|(row, _)| row);
+            if direction < 0 {
+                prompts.filter(|&row| row < top).last()
+            } else {
+                prompts.find(|&row| row > top)
+            }
+        };
+        if let Some(row) = row {
+            self.viewport_offset = self.scrollback.len().saturating_sub(row);
+        }
+    }
+
+    // Selects the output of the latest command that produced any, from its
+    // `C` mark up to the next prompt, and scrolls it into view.
+    pub fn select_last_output(&mut self) {
+        if self.alternate_screen {
+            return;
+        }
+        let last = self.cursor_row();
+        let start = match self
+            .history()
+            .take(last + 1)
+            .enumerate()
+            .filter(|(_, line)| line.marks.output)
+            .map(|(row, _)| row)
+            .last()
+        {
+            Some(start) => start,
+            None => return,
+        };
+        let end = self
+            .history()
+            .enumerate()
+            .skip(start + 1)
+            .find(|(_, line)| line.marks.prompt)
+            .map(|(row, _)| row - 1)
+            .unwrap_or(last)
+            .max(start);
+        self.selection = Some(start..=end);
+        let top = self.scrollback.len() - self.viewport_offset;
+        if start < top || start >= top + self.rows {
+            self.viewport_offset = self.scrollback.len().saturating_sub(start);
+        }
+    }
+
+    // The selected rows that are in the viewport, in viewport coordinates.
+    pub fn visible_selection(&self) -> Option<RangeInclusive<usize>> {
+        let rows = self.selection.clone()?;
+        let top = self.scrollback.len() - self.viewport_offset;
+        let bottom = top + self.rows - 1;
+        if *rows.end() < top || *rows.start() > bottom {
+            return None;
+        }
+        Some(rows.start().max(&top) - top..=rows.end().min(&bottom) - top)
+    }
+
+    // Places an image at the cursor, keeping its size, offset, z-index and
+    // ids. The cursor is not moved.
+    pub fn place_image(&mut self, mut placement: Placement) {
+        placement.id = self.next_image_id;
+        placement.row = self.cursor_row();
+        placement.col = self.cursor.x;
+        placement.alternate = self.alternate_screen;
+        self.next_image_id += 1;
+        self.images.push(placement);
+        self.limit_image_memory();
+    }
+
+    // Keeps the pixels of placed and stored images within `MAX_IMAGE_BYTES`,
+    // counting those that placements share with kitty images once. Stored
+    // images that nothing shows are dropped first, except the newest, which
+    // is usually about to be placed. Then the oldest placements go.
+    pub fn limit_image_memory(&mut self) {
+        loop {
+            let mut bitmaps = HashSet::new();
+            let bytes: usize = self
+                .images
+                .iter()
+                .map(|image| &image.bitmap)
+                .chain(self.kitty_images.bitmaps())
+                .filter(|bitmap| bitmaps.insert(Arc::as_ptr(bitmap)))
+                .map(|bitmap| bitmap.pixels.len())
+                .sum();
+            if bytes <= MAX_IMAGE_BYTES {
+                break;
+            }
+            let placed: HashSet<_> = self
+                .images
+                .iter()
+                .map(|image| Arc::as_ptr(&image.bitmap))
+                .collect();
+            let newest = self.kitty_images.newest();
+            let unused = self
+                .kitty_images
+                .oldest(|id, bitmap| Some(id) != newest && !placed.contains(&Arc::as_ptr(bitmap)));
+            if let Some(id) = unused {
+                self.kitty_images.remove(id);
+            } else if !self.images.is_empty() {
+                self.images.remove(0);
+            } else {
+                break;
+            }
+        }
+    }
+
+    // The number of columns and rows that an image covers.
+    pub fn image_cells(&self, placement: &Placement) -> (usize, usize) {
+        let (cell_width, cell_height) = self.cell_size;
+        let (x, y) = placement.offset;
+        let cols = ((x + placement.width) / cell_width).ceil().max(1.0);
+        let rows = ((y + placement.height) / cell_height).ceil().max(1.0);
+        (cols as usize, rows as usize)
+    }
+
+    // Scales down a size on screen in physical pixels that is too large,
+    // keeping its aspect ratio.
+    pub fn limit_image_size(&self, (width, height): (f64, f64)) -> (f64, f64) {
+        let (cell_width, cell_height) = self.cell_size;
+        let max_width = self.cols as f64 * cell_width * MAX_IMAGE_SCREENS;
+        let max_height = self.rows as f64 * cell_height * MAX_IMAGE_SCREENS;
+        let scale = (max_width / width).min(max_height / height).min(1.0);
+        (width * scale, height * scale)
+    }
+
+    // The images shown on the current screen.
+    pub fn visible_images(&self) -> impl Iterator<Item = &Placement> {
+        let alternate = self.alternate_screen;
+        self.images
+            .iter()
+            .filter(move |image| image.alternate == alternate)
+    }
+
+    // BEL
+    pub fn ring_bell(&mut self) {
+        self.bell = true;
+        self.flash_until = Some(Instant::now() + VISUAL_BELL_DURATION);
+    }
+
+    // Whether the visual bell is showing.
+    pub fn flashing(&self) -> bool {
+        self.flash_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    // Rendering is held back until this time while synchronized output is on.
+    pub fn synchronized_output_deadline(&self) -> Option<Instant> {
+        if !self.modes.get(Mode::SynchronizedOutput) {
+            return None;
+        }
+        self.synchronized_since
+            .map(|since| since + SYNCHRONIZED_OUTPUT_TIMEOUT)
+    }
+
+    // The row of the top of the viewport, counted like image rows.
+    pub fn viewport_top(&self) -> usize {
+        self.scrollback.len() - self.viewport_offset
+    }
 }
@@ -0,0 +1,119 @@
+// The capabilities answered by XTGETTCAP, after xterm-256color. Only what
+// greentty implements is listed: mouse reporting and bracketed paste are
+// left out, so that programs do not turn on modes that would be ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    Bool,
+    Number(u32),
+    String(&'static str),
+}
+
+const CAPABILITIES: &[(&str, Capability)] = &[
+    // The terminal name, an xterm extension.
+    ("TN", Capability::String("xterm-256color")),
+    ("name", Capability::String("xterm-256color")),
+    // Booleans
+    ("am", Capability::Bool),
+    ("bce", Capability::Bool),
+    ("km", Capability::Bool),
+    ("mir", Capability::Bool),
+    ("msgr", Capability::Bool),
+    ("xenl", Capability::Bool),
+    ("AX", Capability::Bool),
+    ("XT", Capability::Bool),
+    ("Tc", Capability::Bool),
+    // Numbers
+    ("colors", Capability::Number(256)),
+    ("Co", Capability::Number(256)),
+    ("cols", Capability::Number(80)),
+    ("it", Capability::Number(8)),
+    ("lines", Capability::Number(24)),
+    ("pairs", Capability::Number(65536)),
+    // Strings
+    ("bel", Capability::String("\x07")),
+    ("blink", Capability::String("\x1b[5m")),
+    ("bold", Capability::String("\x1b[1m")),
+    ("cbt", Capability::String("\x1b[Z")),
+    ("civis", Capability::String("\x1b[?25l")),
+    ("clear", Capability::String("\x1b[H\x1b[2J")),
+    ("cnorm", Capability::String("\x1b[?12l\x1b[?25h")),
+    ("cr", Capability::String("\r")),
+    ("csr", Capability::String("\x1b[%i%p1%d;%p2%dr")),
+    ("cub", Capability::String("\x1b[%p1%dD")),
+    ("cub1", Capability::String("\x08")),
+    ("cud", Capability::String("\x1b[%p1%dB")),
+    ("cud1", Capability::String("\n")),
+    ("cuf", Capability::String("\x1b[%p1%dC")),
+    ("cuf1", Capability::String("\x1b[C")),
+    ("cup", Capability::String("\x1b[%i%p1%d;%p2%dH")),
+    ("cuu", Capability::String("\x1b[%p1%dA")),
+    ("cuu1", Capability::String("\x1b[A")),
+    ("cvvis", Capability::String("\x1b[?12;25h")),
+    ("dch", Capability::String("\x1b[%p1%dP")),
+    ("dch1", Capability::String("\x1b[P")),
+    ("dim", Capability::String("\x1b[2m")),
+    ("dl", Capability::String("\x1b[%p1%dM")),
+    ("dl1", Capability::String("\x1b[M")),
+    ("ech", Capability::String("\x1b[%p1%dX")),
+    ("ed", Capability::String("\x1b[J")),
+    ("el", Capability::String("\x1b[K")),
+    ("el1", Capability::String("\x1b[1K")),
+    ("home", Capability::String("\x1b[H")),
+    ("hpa", Capability::String("\x1b[%i%p1%dG")),
+    ("ht", Capability::String("\t")),
+    ("hts", Capability::String("\x1bH")),
+    ("ich", Capability::String("\x1b[%p1%d@")),
+    ("il", Capability::String("\x1b[%p1%dL")),
+    ("il1", Capability::String("\x1b[L")),
+    ("ind", Capability::String("\n")),
+    ("indn", Capability::String("\x1b[%p1%dS")),
+    ("invis", Capability::String("\x1b[8m")),
+    ("kbs", Capability::String("\x08")),
+    ("op", Capability::String("\x1b[39;49m")),
+    ("rc", Capability::String("\x1b8")),
+    ("rev", Capability::String("\x1b[7m")),
+    ("ri", Capability::String("\x1bM")),
+    ("rin", Capability::String("\x1b[%p1%dT")),
+    ("ritm", Capability::String("\x1b[23m")),
+    ("rmacs", Capability::String("\x1b(B")),
+    ("rmam", Capability::String("\x1b[?7l")),
+    ("rmcup", Capability::String("\x1b[?1049l\x1b[23;0;0t")),
+    ("rmir", Capability::String("\x1b[4l")),
+    ("rmso", Capability::String("\x1b[27m")),
+    ("rmul", Capability::String("\x1b[24m")),
+    ("sc", Capability::String("\x1b7")),
+    (
+        "setab",
+        Capability::String("\x1b[%?%p1%{8}%<%t4%p1%d%e%p1%{16}%<%t10%p1%{8}%-%d%e48;5;%p1%d%;m"),
+    ),
+    (
+        "setaf",
+        Capability::String("\x1b[%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%e38;5;%p1%d%;m"),
+    ),
+    ("sgr0", Capability::String("\x1b(B\x1b[m")),
+    ("sitm", Capability::String("\x1b[3m")),
+    ("smacs", Capability::String("\x1b(0")),
+    ("smam", Capability::String("\x1b[?7h")),
+    ("smcup", Capability::String("\x1b[?1049h\x1b[22;0;0t")),
+    ("smir", Capability::String("\x1b[4h")),
+    ("smso", Capability::String("\x1b[7m")),
+    ("smul", Capability::String("\x1b[4m")),
+    ("smxx", Capability::String("\x1b[9m")),
+    ("rmxx", Capability::String("\x1b[29m")),
+    ("tbc", Capability::String("\x1b[3g")),
+    ("tsl", Capability::String("\x1b]2;")),
+    ("fsl", Capability::String("\x07")),
+    ("vpa", Capability::String("\x1b[%i%p1%dd")),
+    // Extensions
+    ("Ms", Capability::String("\x1b]52;%p1%s;%p2%s\x07")),
+    ("Se", Capability::String("\x1b[2 q")),
+    ("Ss", Capability::String("\x1b[%p1%d q")),
+    ("Sync", Capability::String("\x1b[?2026%?%p1%{1}%-%tl%eh%;")),
+];
+
+pub fn lookup(name: &str) -> Option<Capability> {
+    CAPABILITIES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, capability)| capability)
+}
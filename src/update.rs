@@ -1,55 +1,2287 @@
 use crate::action::Action;
-use crate::state::State;
+use crate::charset::Charset;
+use crate::clipboard::{self, Selection};
+use crate::graphics::{self, iterm, kitty, sixel, Placement};
+use crate::mode::{Mode, Modes};
+use crate::notify::Notification;
+use crate::palette::{self, Rgb};
+use crate::state::{Attributes, Color, State};
+use crate::terminfo::{self, Capability};
+
+use std::sync::Arc;
+use std::time::Instant;
 
 pub fn update(state: &mut State, action: &Action) {
     match action {
         Action::Print(c) => {
-            while state.cursor.y >= state.lines.len() {
-                state.lines.push(vec![]);
-            }
-            while state.cursor.x >= state.lines[state.cursor.y].len() {
-                state.lines[state.cursor.y].push(' ');
-            }
-            state.lines[state.cursor.y][state.cursor.x] = *c;
-            state.cursor.x += 1;
+            state.print(*c);
         }
         Action::Execute(byte) => {
             match byte {
+                7 /* BEL */ => {
+                    state.ring_bell();
+                }
                 8 /* BS */ => {
-                    state.cursor.x -= 1;
+                    state.backspace();
                 }
-                10 /* LF */ => {
-                    state.cursor.y += 1;
+                9 /* HT */ => {
+                    state.tab_forward(1);
+                }
+                10..=12 /* LF, VT, FF */ => {
+                    state.linefeed();
+                    if state.modes.get(Mode::LineFeedNewLine) {
+                        state.carriage_return();
+                    }
                 }
                 13 /* CR */ => {
-                    state.cursor.x = 0;
+                    state.carriage_return();
+                }
+                14 /* SO */ => {
+                    state.active_charset = 1;
+                }
+                15 /* SI */ => {
+                    state.active_charset = 0;
                 }
                 _ => {}
             }
         }
-        Action::DispatchCSI(params, _intermediates, _ignore, c) => {
-            match c {
-                'H' => {
-                    let y = if params.len() >= 1 { params[0] - 1 } else { 0 } as usize;
-                    let x = if params.len() >= 2 { params[1] - 1 } else { 0 } as usize;
-                    state.cursor.y = y;
-                    state.cursor.x = x;
-                }
-                'X' => {
-                    // FIXME
-                    // let s = if params.len() >= 1 { params[0] } else { 1 } as usize;
-                    while state.cursor.y >= state.lines.len() {
-                        state.lines.push(vec![]);
+        Action::DispatchCSI(params, intermediates, _ignore, c) => match c {
+            // CUU: Cursor Up
+            'A' => {
+                state.move_up(param(params, 0, 1) as usize);
+            }
+            // CUD: Cursor Down, VPR: Line Position Relative
+            'B' | 'e' => {
+                state.move_down(param(params, 0, 1) as usize);
+            }
+            // CUF: Cursor Forward, HPR: Character Position Relative
+            'C' | 'a' => {
+                state.move_right(param(params, 0, 1) as usize);
+            }
+            // CUB: Cursor Backward
+            'D' => {
+                state.move_left(param(params, 0, 1) as usize);
+            }
+            // CNL: Cursor Next Line
+            'E' => {
+                state.move_down(param(params, 0, 1) as usize);
+                state.carriage_return();
+            }
+            // CPL: Cursor Previous Line
+            'F' => {
+                state.move_up(param(params, 0, 1) as usize);
+                state.carriage_return();
+            }
+            // CHA: Cursor Horizontal Absolute, HPA: Character Position Absolute
+            'G' | '`' => {
+                state.goto_column(param(params, 0, 1) as usize - 1);
+            }
+            // CUP: Cursor Position, HVP: Horizontal and Vertical Position
+            'H' | 'f' => {
+                let y = param(params, 0, 1) - 1;
+                let x = param(params, 1, 1) - 1;
+                state.goto(x as usize, y as usize);
+            }
+            // VPA: Line Position Absolute
+            'd' => {
+                state.goto_row(param(params, 0, 1) as usize - 1);
+            }
+            'J' => {
+                state.erase_in_display(param(params, 0, 0));
+            }
+            'K' => {
+                state.erase_in_line(param(params, 0, 0));
+            }
+            'X' => {
+                state.erase_chars(param(params, 0, 1) as usize);
+            }
+            '@' => {
+                state.insert_chars(param(params, 0, 1) as usize);
+            }
+            'P' => {
+                state.delete_chars(param(params, 0, 1) as usize);
+            }
+            'L' => {
+                state.insert_lines(param(params, 0, 1) as usize);
+            }
+            'M' => {
+                state.delete_lines(param(params, 0, 1) as usize);
+            }
+            'h' | 'l' if intermediates.is_empty() => {
+                let enable = *c == 'h';
+                for param in params {
+                    if let Some(mode) = Mode::from_ansi(param[0]) {
+                        state.modes.set(mode, enable);
                     }
-                    state.lines[state.cursor.y].resize(state.cursor.x, ' ');
                 }
-                'C' => {
-                    let s = if params.len() >= 1 { params[0] } else { 1 } as usize;
-                    state.cursor.x += s;
+            }
+            'h' | 'l' if intermediates[..] == [b'?'] => {
+                let enable = *c == 'h';
+                for param in params {
+                    set_private_mode(state, param[0], enable);
+                }
+            }
+            // DA1: Primary Device Attributes
+            'c' if intermediates.is_empty() && param(params, 0, 0) == 0 => {
+                // VT220 with sixel graphics and ANSI colour.
+                state.respond("\x1b[?62;4;22c");
+            }
+            // DA2: Secondary Device Attributes
+            'c' if intermediates[..] == [b'>'] && param(params, 0, 0) == 0 => {
+                state.respond("\x1b[>1;10;0c");
+            }
+            // DSR: Device Status Report
+            'n' if intermediates.is_empty() => match param(params, 0, 0) {
+                5 => state.respond("\x1b[0n"),
+                6 => {
+                    let (x, y) = state.reported_cursor();
+                    state.respond(&format!("\x1b[{};{}R", y + 1, x + 1));
                 }
                 _ => {}
+            },
+            // DECXCPR: Extended Cursor Position Report
+            'n' if intermediates[..] == [b'?'] && param(params, 0, 0) == 6 => {
+                let (x, y) = state.reported_cursor();
+                state.respond(&format!("\x1b[?{};{}R", y + 1, x + 1));
+            }
+            // XTVERSION
+            'q' if intermediates[..] == [b'>'] && param(params, 0, 0) == 0 => {
+                state.respond(&format!(
+                    "\x1bP>|{}({})\x1b\\",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                ));
+            }
+            // DECSCUSR: Set Cursor Style
+            'q' if intermediates[..] == [b' '] => {
+                let style = param(params, 0, 0);
+                if (0..=6).contains(&style) {
+                    state.cursor_style = style as u8;
+                }
+            }
+            // XTWINOPS
+            't' if intermediates.is_empty() => {
+                window_ops(state, params);
+            }
+            // DECRQM: Request Mode
+            'p' if intermediates[..] == [b'$'] => {
+                let mode = param(params, 0, 0);
+                let value = match Mode::from_ansi(mode) {
+                    Some(mode) => mode_value(&state.modes, mode),
+                    None => 0,
+                };
+                state.respond(&format!("\x1b[{};{}$y", mode, value));
+            }
+            'p' if intermediates[..] == [b'?', b'$'] => {
+                let mode = param(params, 0, 0);
+                let value = match (mode, Mode::from_private(mode)) {
+                    (47, _) | (1047, _) | (1049, _) => 2 - state.alternate_screen as i64,
+                    (_, Some(mode)) => mode_value(&state.modes, mode),
+                    _ => 0,
+                };
+                state.respond(&format!("\x1b[?{};{}$y", mode, value));
+            }
+            // CHT: Cursor Forward Tabulation
+            'I' => {
+                state.tab_forward(param(params, 0, 1) as usize);
+            }
+            // CBT: Cursor Backward Tabulation
+            'Z' => {
+                state.tab_backward(param(params, 0, 1) as usize);
+            }
+            // TBC: Tab Clear
+            'g' => {
+                state.clear_tabs(param(params, 0, 0));
+            }
+            'm' => {
+                sgr(state, params);
+            }
+            // SCOSC: Save Current Cursor Position
+            's' if intermediates.is_empty() => {
+                state.save_cursor();
+            }
+            // SCORC: Restore Saved Cursor Position
+            'u' if intermediates.is_empty() => {
+                state.restore_cursor();
+            }
+            'r' if intermediates.is_empty() => {
+                let top = param(params, 0, 1) - 1;
+                let bottom = param(params, 1, state.rows as i64);
+                state.set_scroll_region(top as usize, bottom as usize);
+            }
+            'S' if intermediates.is_empty() => {
+                state.scroll_up(param(params, 0, 1) as usize);
+            }
+            // `CSI Ps ; Ps ; Ps ; Ps ; Ps T` is xterm's mouse highlight tracking.
+            'T' if intermediates.is_empty() && params.len() <= 1 => {
+                state.scroll_down(param(params, 0, 1) as usize);
+            }
+            _ => {}
+        },
+        Action::DispatchESC(intermediates, _ignore, byte) => match (&intermediates[..], byte) {
+            // DECSC: Save Cursor
+            ([], b'7') => {
+                state.save_cursor();
+            }
+            // DECRC: Restore Cursor
+            ([], b'8') => {
+                state.restore_cursor();
+            }
+            // IND: Index
+            ([], b'D') => {
+                state.linefeed();
+            }
+            // NEL: Next Line
+            ([], b'E') => {
+                state.carriage_return();
+                state.linefeed();
+            }
+            // HTS: Horizontal Tab Set
+            ([], b'H') => {
+                state.set_tab();
+            }
+            // SS2: Single Shift Two
+            ([], b'N') => {
+                state.single_shift = Some(2);
+            }
+            // SS3: Single Shift Three
+            ([], b'O') => {
+                state.single_shift = Some(3);
+            }
+            // SCS: Select Character Set
+            ([i @ b'('..=b'+'], byte) => {
+                if let Some(charset) = Charset::from_designator(*byte) {
+                    state.charsets[(i - b'(') as usize] = charset;
+                }
+            }
+            // RI: Reverse Index
+            ([], b'M') => {
+                state.reverse_index();
+            }
+            _ => {}
+        },
+        Action::Resize(cols, rows) => {
+            state.resize(*cols, *rows);
+        }
+        Action::DispatchDCS(params, intermediates, _ignore, c, data) => {
+            dcs(state, params, intermediates, *c, data);
+        }
+        Action::DispatchOSC(params, bell_terminated) => {
+            osc(state, params, *bell_terminated);
+        }
+        Action::DispatchAPC(data) => {
+            // Only the kitty graphics protocol uses APC.
+            if let Some(data) = data.strip_prefix(b"G") {
+                kitty_graphics(state, data);
+            }
+        }
+        Action::SetCellSize(width, height, dpi_factor) => {
+            state.cell_size = (*width, *height);
+            state.dpi_factor = *dpi_factor;
+        }
+        Action::MouseMoved(x, y) => {
+            state.move_mouse(*x, *y);
+        }
+        Action::MouseLeft() => {
+            state.mouse = None;
+        }
+        Action::ResponsesSent() => {
+            state.responses.clear();
+        }
+        Action::ClipboardRequestsHandled() => {
+            state.clipboard_requests.clear();
+        }
+        Action::NotificationsHandled() => {
+            state.bell = false;
+            state.notifications.clear();
+        }
+        Action::ScrollLines(n) => {
+            state.scroll_viewport(*n);
+        }
+        Action::ScrollPages(n) => {
+            state.scroll_viewport(n * state.rows as i64);
+        }
+        Action::ScrollToBottom() => {
+            // Sent on typing, which also drops the selection.
+            state.viewport_offset = 0;
+            state.selection = None;
+        }
+        Action::JumpToPrompt(direction) => {
+            state.jump_to_prompt(*direction);
+        }
+        Action::SelectLastOutput() => {
+            state.select_last_output();
+        }
+        _ => {}
+    }
+}
+
+// Returns the `index`-th parameter, or `default` when it is omitted or zero.
+fn param(params: &[Vec<i64>], index: usize, default: i64) -> i64 {
+    match params.get(index).and_then(|p| p.first()) {
+        Some(&p) if p != 0 => p,
+        _ => default,
+    }
+}
+
+// Places a sixel or iTerm2 image at the cursor, which moves to the line
+// below the image, keeping its column.
+fn place_inline_image(state: &mut State, placement: Placement) {
+    let (_, rows) = state.image_cells(&placement);
+    state.place_image(placement);
+    let x = state.cursor.x;
+    for _ in 0..rows.min(state.rows) {
+        state.linefeed();
+    }
+    state.goto_column(x);
+}
+
+// DCS: Device Control String
+fn dcs(state: &mut State, params: &[Vec<i64>], intermediates: &[u8], c: char, data: &[u8]) {
+    match (intermediates, c) {
+        // Sixel graphics, drawn at one image pixel per screen pixel.
+        (b"", 'q') => {
+            if let Some(bitmap) = sixel::decode(params, data) {
+                place_inline_image(state, Placement::new(Arc::new(bitmap)));
+            }
+        }
+        // DECRQSS: Request Status String
+        (b"$", 'q') => {
+            let reply = match data {
+                b"m" => Some(format!("{}m", sgr_report(state))),
+                b"r" => Some(format!("{};{}r", state.scroll_top + 1, state.scroll_bottom)),
+                b" q" => Some(format!("{} q", state.cursor_style.max(1))),
+                _ => None,
+            };
+            match reply {
+                Some(reply) => state.respond(&format!("\x1bP1$r{}\x1b\\", reply)),
+                None => state.respond("\x1bP0$r\x1b\\"),
+            }
+        }
+        // XTGETTCAP: names are hex-encoded and separated by `;`.
+        (b"+", 'q') => {
+            for name in data.split(|&b| b == b';') {
+                let capability = hex_decode(name)
+                    .and_then(|name| String::from_utf8(name).ok())
+                    .and_then(|name| terminfo::lookup(&name));
+                let name = String::from_utf8_lossy(name);
+                let reply = match capability {
+                    Some(Capability::Bool) => format!("1+r{}", name),
+                    Some(Capability::Number(n)) => {
+                        format!("1+r{}={}", name, hex_encode(n.to_string().as_bytes()))
+                    }
+                    Some(Capability::String(s)) => {
+                        format!("1+r{}={}", name, hex_encode(s.as_bytes()))
+                    }
+                    None => format!("0+r{}", name),
+                };
+                state.respond(&format!("\x1bP{}\x1b\\", reply));
             }
         }
         _ => {}
     }
 }
+
+// Kitty graphics protocol: `ESC _ G key=value,... ; payload ESC \`
+fn kitty_graphics(state: &mut State, data: &[u8]) {
+    let (command, payload) = match kitty::parse(data) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let (command, payload) = match command.action {
+        b't' | b'T' | b'q' => match state.kitty_images.receive(command, payload) {
+            Some(upload) => upload,
+            None => return,
+        },
+        _ => (command, vec![]),
+    };
+    let result = match command.action {
+        b't' | b'T' => kitty::load(&command, &payload).and_then(|bitmap| {
+            let id = match (command.image_id, command.image_number) {
+                (0, 0) if command.action == b't' => return Ok(0),
+                (0, _) => state.kitty_images.unused_id(),
+                (id, _) => id,
+            };
+            state.kitty_images.insert(id, command.image_number, bitmap);
+            state.limit_image_memory();
+            if command.action == b'T' {
+                kitty_place(state, &command, id)?;
+            }
+            Ok(id)
+        }),
+        b'q' => kitty::load(&command, &payload).map(|_| command.image_id),
+        b'p' => {
+            let id = match command.image_id {
+                0 => state.kitty_images.by_number(command.image_number),
+                id => Some(id),
+            };
+            match id {
+                Some(id) => kitty_place(state, &command, id).map(|()| id),
+                None => Err("ENOENT:no such image".to_string()),
+            }
+        }
+        b'd' => {
+            kitty_delete(state, &command);
+            return;
+        }
+        _ => Err("EINVAL:unknown action".to_string()),
+    };
+    // Only commands that name an image are answered, so that the replies
+    // cannot end up as keyboard input to unsuspecting programs.
+    if command.image_id == 0 && command.image_number == 0 {
+        return;
+    }
+    let mut keys = match &result {
+        Ok(id) if *id != 0 => format!("i={}", id),
+        _ if command.image_id != 0 => format!("i={}", command.image_id),
+        _ => String::new(),
+    };
+    let message = match result {
+        Ok(_) if command.quiet >= 1 => return,
+        Err(_) if command.quiet >= 2 => return,
+        Ok(_) => "OK".to_string(),
+        Err(error) => error,
+    };
+    if command.image_number != 0 {
+        if !keys.is_empty() {
+            keys.push(',');
+        }
+        keys.push_str(&format!("I={}", command.image_number));
+    }
+    if command.placement_id != 0 {
+        keys.push_str(&format!(",p={}", command.placement_id));
+    }
+    state.respond(&format!("\x1b_G{};{}\x1b\\", keys, message));
+}
+
+// Shows a transmitted image at the cursor, replacing the placement with the
+// same image and placement ids.
+fn kitty_place(state: &mut State, command: &kitty::Command, id: u32) -> Result<(), String> {
+    let bitmap = state
+        .kitty_images
+        .get(id)
+        .ok_or_else(|| "ENOENT:no such image".to_string())?;
+    let bitmap = if (command.x, command.y, command.width, command.height) == (0, 0, 0, 0) {
+        bitmap
+    } else {
+        let width = match command.width {
+            0 => bitmap.width,
+            width => width,
+        };
+        let height = match command.height {
+            0 => bitmap.height,
+            height => height,
+        };
+        Arc::new(bitmap.crop(command.x, command.y, width, height))
+    };
+    if bitmap.width == 0 || bitmap.height == 0 {
+        return Err("EINVAL:empty source rectangle".to_string());
+    }
+    let (cell_width, cell_height) = state.cell_size;
+    let (width, height) = (f64::from(bitmap.width), f64::from(bitmap.height));
+    // A size given in cells in one direction only keeps the aspect ratio.
+    let size = match (command.cols, command.rows) {
+        (0, 0) => (width, height),
+        (cols, 0) => {
+            let w = f64::from(cols) * cell_width;
+            (w, height * w / width)
+        }
+        (0, rows) => {
+            let h = f64::from(rows) * cell_height;
+            (width * h / height, h)
+        }
+        (cols, rows) => (f64::from(cols) * cell_width, f64::from(rows) * cell_height),
+    };
+    let size = state.limit_image_size(size);
+    let mut placement = Placement::new(bitmap);
+    placement.width = size.0;
+    placement.height = size.1;
+    placement.offset = (
+        f64::from(command.offset.0).min(cell_width - 1.0).max(0.0),
+        f64::from(command.offset.1).min(cell_height - 1.0).max(0.0),
+    );
+    placement.z_index = command.z_index;
+    placement.image_id = id;
+    placement.placement_id = command.placement_id;
+    if command.placement_id != 0 {
+        state
+            .images
+            .retain(|p| (p.image_id, p.placement_id) != (id, command.placement_id));
+    }
+    // The cursor moves to the cell after the bottom right corner.
+    let (cols, rows) = state.image_cells(&placement);
+    state.place_image(placement);
+    if command.move_cursor {
+        let x = state.cursor.x;
+        for _ in 1..rows.min(state.rows) {
+            state.linefeed();
+        }
+        state.goto_column(x + cols);
+    }
+    Ok(())
+}
+
+fn kitty_delete(state: &mut State, command: &kitty::Command) {
+    let top = state.scrollback.len();
+    let cursor = (state.cursor.x, state.cursor_row());
+    // Cells given in the command are 1-based screen coordinates.
+    let x = (command.x as usize).saturating_sub(1);
+    let y = top + (command.y as usize).saturating_sub(1);
+    let image_id = match command.delete.to_ascii_lowercase() {
+        b'n' => state.kitty_images.by_number(command.image_number),
+        _ => Some(command.image_id),
+    };
+    let alternate = state.alternate_screen;
+    let covers = |state: &State, p: &Placement, col: Option<usize>, row: Option<usize>| {
+        let (cols, rows) = state.image_cells(p);
+        col.is_none_or(|col| (p.col..p.col + cols).contains(&col))
+            && row.is_none_or(|row| (p.row..p.row + rows).contains(&row))
+    };
+    let mut deleted = vec![];
+    let mut kept = vec![];
+    for p in std::mem::take(&mut state.images) {
+        let matches = p.alternate == alternate
+            && match command.delete.to_ascii_lowercase() {
+                b'a' => p.row + state.image_cells(&p).1 > top,
+                b'i' | b'n' => {
+                    p.image_id != 0
+                        && Some(p.image_id) == image_id
+                        && (command.placement_id == 0 || p.placement_id == command.placement_id)
+                }
+                b'c' => covers(state, &p, Some(cursor.0), Some(cursor.1)),
+                b'p' => covers(state, &p, Some(x), Some(y)),
+                b'x' => covers(state, &p, Some(x), None),
+                b'y' => covers(state, &p, None, Some(y)),
+                b'z' => p.z_index == command.z_index,
+                _ => false,
+            };
+        if matches {
+            deleted.push(p.image_id);
+        } else {
+            kept.push(p);
+        }
+    }
+    state.images = kept;
+    // Upper case also frees the images that are no longer shown.
+    if command.delete.is_ascii_uppercase() {
+        if let Some(id) = image_id.filter(|_| b"IN".contains(&command.delete)) {
+            deleted.push(id);
+        }
+        for id in deleted {
+            if id != 0 && !state.images.iter().any(|p| p.image_id == id) {
+                state.kitty_images.remove(id);
+            }
+        }
+    }
+}
+
+// The SGR parameters that recreate the current pen, as reported by DECRQSS.
+fn sgr_report(state: &State) -> String {
+    let pen = &state.pen;
+    let mut params = vec!["0".to_string()];
+    let attrs = [
+        (pen.attrs.bold, 1),
+        (pen.attrs.dim, 2),
+        (pen.attrs.italic, 3),
+        (pen.attrs.underline, 4),
+        (pen.attrs.blink, 5),
+        (pen.attrs.inverse, 7),
+        (pen.attrs.hidden, 8),
+        (pen.attrs.strikethrough, 9),
+    ];
+    for &(set, n) in &attrs {
+        if set {
+            params.push(n.to_string());
+        }
+    }
+    for &(color, base) in &[(pen.fg, 30), (pen.bg, 40)] {
+        match color {
+            Color::Default => {}
+            Color::Indexed(n) if n < 8 => params.push((base + n as i64).to_string()),
+            Color::Indexed(n) if n < 16 => params.push((base + 60 + n as i64 - 8).to_string()),
+            Color::Indexed(n) => params.push(format!("{};5;{}", base + 8, n)),
+            Color::Rgb(r, g, b) => params.push(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        }
+    }
+    params.join(";")
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// Decodes `%XX` escapes, leaving malformed ones as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Replies to a colour query in the same form as the request.
+fn report_color(state: &mut State, prefix: &str, color: Rgb, bell_terminated: bool) {
+    let terminator = if bell_terminated { "\x07" } else { "\x1b\\" };
+    let reply = format!(
+        "\x1b]{};{}{}",
+        prefix,
+        palette::format_color(color),
+        terminator
+    );
+    state.respond(&reply);
+}
+
+// OSC: Operating System Command
+fn osc(state: &mut State, params: &[Vec<u8>], bell_terminated: bool) {
+    let command = match params.first() {
+        Some(command) => String::from_utf8_lossy(command),
+        None => return,
+    };
+    // The parser splits the text at `;`, which may be part of the text itself.
+    let text = String::from_utf8_lossy(&params[1..].join(&b';')).into_owned();
+    match &command[..] {
+        "0" => {
+            state.title = text.clone();
+            state.icon_name = text;
+        }
+        "1" => state.icon_name = text,
+        "2" => state.title = text,
+        "7" => {
+            // OSC 7 ; file://host/path. The host is not checked, since the
+            // path is only used when it exists.
+            let path = text
+                .strip_prefix("file://")
+                .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+                .map(percent_decode);
+            if let Some(path) = path {
+                state.cwd = Some(path);
+            }
+        }
+        "8" => {
+            // OSC 8 ; params ; URI, where params are `:`-separated `key=value`.
+            let mut parts = text.splitn(2, ';');
+            let id = parts
+                .next()
+                .unwrap_or("")
+                .split(':')
+                .find_map(|p| p.strip_prefix("id="))
+                .map(str::to_string);
+            let uri = parts.next().unwrap_or("").to_string();
+            state.set_hyperlink(id, uri);
+        }
+        "4" => {
+            // OSC 4 ; c ; spec, repeated for any number of colours.
+            for pair in params[1..].chunks(2) {
+                let index = String::from_utf8_lossy(&pair[0]).parse::<u8>();
+                let spec = pair.get(1).map(|spec| String::from_utf8_lossy(spec));
+                if let (Ok(index), Some(spec)) = (index, spec) {
+                    if spec == "?" {
+                        let color = state.palette.colors[index as usize];
+                        let prefix = format!("4;{}", index);
+                        report_color(state, &prefix, color, bell_terminated);
+                    } else if let Some(color) = palette::parse_color(&spec) {
+                        state.palette.colors[index as usize] = color;
+                    }
+                }
+            }
+        }
+        "10" | "11" | "12" => {
+            // Further specs go to the following dynamic colours, so that
+            // `OSC 10 ; ? ; ?` queries both the foreground and background.
+            let first = command.parse::<usize>().unwrap_or(10);
+            for (number, spec) in (first..=12).zip(&params[1..]) {
+                let spec = String::from_utf8_lossy(spec);
+                if spec == "?" {
+                    let palette = &state.palette;
+                    let color = match number {
+                        10 => palette.foreground,
+                        11 => palette.background,
+                        _ => palette.cursor.unwrap_or(palette.foreground),
+                    };
+                    report_color(state, &number.to_string(), color, bell_terminated);
+                } else if let Some(color) = palette::parse_color(&spec) {
+                    match number {
+                        10 => state.palette.foreground = color,
+                        11 => state.palette.background = color,
+                        _ => state.palette.cursor = Some(color),
+                    }
+                }
+            }
+        }
+        "104" => {
+            // Without parameters, every colour is reset.
+            let indices: Vec<u8> = params[1..]
+                .iter()
+                .filter_map(|index| String::from_utf8_lossy(index).parse().ok())
+                .collect();
+            if indices.is_empty() {
+                state.palette.colors = palette::Palette::default().colors;
+            }
+            for index in indices {
+                state.palette.reset_color(index);
+            }
+        }
+        "110" => state.palette.foreground = palette::Palette::default().foreground,
+        "111" => state.palette.background = palette::Palette::default().background,
+        "112" => state.palette.cursor = None,
+        "133" => {
+            // OSC 133 ; A|B|C, or OSC 133 ; D [; exit status].
+            let mark = params.get(1).and_then(|mark| mark.first());
+            match mark {
+                Some(b'D') => {
+                    let status = params
+                        .get(2)
+                        .and_then(|status| String::from_utf8_lossy(status).parse().ok());
+                    state.finish_command(status);
+                }
+                Some(&mark) => state.mark_line(mark as char),
+                None => {}
+            }
+        }
+        // OSC 9 ; 4 ; ... is ConEmu's progress report, not a message.
+        "9" if params.get(1).map(|p| &p[..]) != Some(b"4") => {
+            state.notifications.push(Notification {
+                title: state.title.clone(),
+                body: text,
+            });
+        }
+        "777" => {
+            // OSC 777 ; notify ; title ; body
+            let mut parts = text.splitn(3, ';');
+            if parts.next() == Some("notify") {
+                let title = parts.next().unwrap_or("").to_string();
+                let body = parts.next().unwrap_or("").to_string();
+                state.notifications.push(Notification { title, body });
+            }
+        }
+        "1337" => {
+            // OSC 1337 ; File = args : base64. Other iTerm2 commands are
+            // ignored.
+            let args = params[1..].join(&b';');
+            let file = match args.strip_prefix(b"File=").and_then(iterm::parse) {
+                Some(file) if file.inline => file,
+                _ => return,
+            };
+            let bitmap = match graphics::decode_image(&file.data) {
+                Ok(bitmap) if bitmap.width > 0 && bitmap.height > 0 => bitmap,
+                _ => return,
+            };
+            let (cell_width, cell_height) = state.cell_size;
+            let screen = (
+                state.cols as f64 * cell_width,
+                state.rows as f64 * cell_height,
+            );
+            let image = (f64::from(bitmap.width), f64::from(bitmap.height));
+            let mut placement = Placement::new(Arc::new(bitmap));
+            let size = iterm::size(&file, image, state.cell_size, screen);
+            let (width, height) = state.limit_image_size(size);
+            placement.width = width;
+            placement.height = height;
+            place_inline_image(state, placement);
+        }
+        "52" => {
+            // OSC 52 ; Pc ; Pd, where Pd is base64 or `?` to query.
+            let mut parts = text.splitn(2, ';');
+            let selections = Selection::parse_all(parts.next().unwrap_or(""));
+            let request = match (parts.next(), selections.first()) {
+                (_, None) => return,
+                (Some("?"), Some(&selection)) => {
+                    clipboard::Request::Query(selection, bell_terminated)
+                }
+                (data, _) => {
+                    // Anything that is not valid base64 clears the selection.
+                    let text = base64::decode(data.unwrap_or(""))
+                        .ok()
+                        .and_then(|data| String::from_utf8(data).ok())
+                        .unwrap_or_default();
+                    clipboard::Request::Set(selections, text)
+                }
+            };
+            state.clipboard_requests.push(request);
+        }
+        _ => {}
+    }
+}
+
+// The maximum depth of the XTWINOPS title stacks, as in xterm.
+const TITLE_STACK_LIMIT: usize = 10;
+
+// XTWINOPS: Window manipulation. Only the title stack operations are
+// supported.
+fn window_ops(state: &mut State, params: &[Vec<i64>]) {
+    let which = param(params, 1, 0);
+    let (icon_name, title) = (which == 0 || which == 1, which == 0 || which == 2);
+    match param(params, 0, 0) {
+        22 => {
+            if icon_name && state.icon_name_stack.len() < TITLE_STACK_LIMIT {
+                let name = state.icon_name.clone();
+                state.icon_name_stack.push(name);
+            }
+            if title && state.title_stack.len() < TITLE_STACK_LIMIT {
+                let name = state.title.clone();
+                state.title_stack.push(name);
+            }
+        }
+        23 => {
+            if icon_name {
+                if let Some(name) = state.icon_name_stack.pop() {
+                    state.icon_name = name;
+                }
+            }
+            if title {
+                if let Some(name) = state.title_stack.pop() {
+                    state.title = name;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// DECSET / DECRST
+fn set_private_mode(state: &mut State, mode: i64, enable: bool) {
+    match mode {
+        6 => {
+            state.modes.set(Mode::Origin, enable);
+            state.goto(0, 0);
+        }
+        47 => state.switch_screen(enable),
+        1047 => {
+            if !enable && state.alternate_screen {
+                state.clear_screen();
+            }
+            state.switch_screen(enable);
+        }
+        1049 => {
+            if enable {
+                state.save_cursor();
+                state.switch_screen(true);
+                state.clear_screen();
+            } else {
+                state.switch_screen(false);
+                state.restore_cursor();
+            }
+        }
+        2026 => {
+            state.synchronized_since = if enable {
+                state.synchronized_since.or_else(|| Some(Instant::now()))
+            } else {
+                None
+            };
+            state.modes.set(Mode::SynchronizedOutput, enable);
+        }
+        _ => {
+            if let Some(mode) = Mode::from_private(mode) {
+                state.modes.set(mode, enable);
+            }
+        }
+    }
+}
+
+// The DECRPM value of a recognized mode: 1 = set, 2 = reset.
+fn mode_value(modes: &Modes, mode: Mode) -> i64 {
+    if modes.get(mode) {
+        1
+    } else {
+        2
+    }
+}
+
+// SGR: Select Graphic Rendition
+fn sgr(state: &mut State, params: &[Vec<i64>]) {
+    if params.is_empty() {
+        state.pen.fg = Color::Default;
+        state.pen.bg = Color::Default;
+        state.pen.attrs = Attributes::default();
+        return;
+    }
+
+    let mut params = params.iter();
+    while let Some(param) = params.next() {
+        let attrs = &mut state.pen.attrs;
+        match param[0] {
+            0 => {
+                state.pen.fg = Color::Default;
+                state.pen.bg = Color::Default;
+                *attrs = Attributes::default();
+            }
+            1 => attrs.bold = true,
+            2 => attrs.dim = true,
+            3 => attrs.italic = true,
+            // `4:0` turns underlining off, the other styles (`4:1` .. `4:5`)
+            // are drawn as a single underline.
+            4 => attrs.underline = param.get(1) != Some(&0),
+            5 | 6 => attrs.blink = true,
+            7 => attrs.inverse = true,
+            8 => attrs.hidden = true,
+            9 => attrs.strikethrough = true,
+            21 => attrs.underline = true,
+            22 => {
+                attrs.bold = false;
+                attrs.dim = false;
+            }
+            23 => attrs.italic = false,
+            24 => attrs.underline = false,
+            25 => attrs.blink = false,
+            27 => attrs.inverse = false,
+            28 => attrs.hidden = false,
+            29 => attrs.strikethrough = false,
+            n @ 30..=37 => state.pen.fg = Color::Indexed((n - 30) as u8),
+            38 => {
+                if let Some(color) = extended_color(param, &mut params) {
+                    state.pen.fg = color;
+                }
+            }
+            39 => state.pen.fg = Color::Default,
+            n @ 40..=47 => state.pen.bg = Color::Indexed((n - 40) as u8),
+            48 => {
+                if let Some(color) = extended_color(param, &mut params) {
+                    state.pen.bg = color;
+                }
+            }
+            49 => state.pen.bg = Color::Default,
+            n @ 90..=97 => state.pen.fg = Color::Indexed((n - 90 + 8) as u8),
+            n @ 100..=107 => state.pen.bg = Color::Indexed((n - 100 + 8) as u8),
+            _ => {}
+        }
+    }
+}
+
+// Parses the colour following SGR 38/48, either from the subparameters
+// (`38:5:n`, `38:2::r:g:b`, `38:2:r:g:b`) or from the following parameters
+// (`38;5;n`, `38;2;r;g;b`).
+fn extended_color<'a, I>(param: &[i64], rest: &mut I) -> Option<Color>
+where
+    I: Iterator<Item = &'a Vec<i64>>,
+{
+    let values: Vec<i64> = if param.len() > 1 {
+        match (param[1], param.len()) {
+            // The colour space id is optional in the colon form.
+            (2, 6) => vec![2, param[3], param[4], param[5]],
+            _ => param[1..].to_vec(),
+        }
+    } else {
+        let kind = rest.next()?[0];
+        let count = match kind {
+            2 => 3,
+            5 => 1,
+            _ => 0,
+        };
+        let mut values = vec![kind];
+        for _ in 0..count {
+            values.push(rest.next()?[0]);
+        }
+        values
+    };
+
+    let component = |v: i64| v.clamp(0, 255) as u8;
+    match values[..] {
+        [5, n] => Some(Color::Indexed(component(n))),
+        [2, r, g, b] => Some(Color::Rgb(component(r), component(g), component(b))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Cell;
+
+    fn csi(state: &mut State, params: &[i64], c: char) {
+        let params = params.iter().map(|&p| vec![p]).collect();
+        update(state, &Action::DispatchCSI(params, vec![], false, c));
+    }
+
+    fn feed(state: &mut State, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' => update(state, &Action::Execute(10)),
+                '\r' => update(state, &Action::Execute(13)),
+                c => update(state, &Action::Print(c)),
+            }
+        }
+    }
+
+    fn text(state: &State) -> Vec<String> {
+        state
+            .lines
+            .iter()
+            .map(|line| line.iter().map(|cell| cell.c).collect())
+            .collect()
+    }
+
+    fn filled(cols: usize, rows: usize) -> State {
+        let mut state = State::new(cols, rows, 100);
+        for y in 0..rows {
+            let c = (b'a' + y as u8) as char;
+            feed(&mut state, &c.to_string().repeat(cols));
+            if y + 1 < rows {
+                feed(&mut state, "\r\n");
+            }
+        }
+        state
+    }
+
+    #[test]
+    fn ech_erases_n_characters_without_moving_the_cursor() {
+        let mut state = filled(6, 1);
+        csi(&mut state, &[1, 2], 'H');
+        csi(&mut state, &[3], 'X');
+        assert_eq!(text(&state), vec!["a   aa"]);
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 0));
+    }
+
+    #[test]
+    fn ech_defaults_to_one_and_clamps_at_the_right_margin() {
+        let mut state = filled(4, 1);
+        csi(&mut state, &[1, 2], 'H');
+        csi(&mut state, &[], 'X');
+        assert_eq!(text(&state), vec!["a aa"]);
+        csi(&mut state, &[100], 'X');
+        assert_eq!(text(&state), vec!["a   "]);
+    }
+
+    #[test]
+    fn el_erases_right_left_and_whole_line() {
+        let mut state = filled(5, 3);
+        csi(&mut state, &[1, 3], 'H');
+        csi(&mut state, &[], 'K');
+        csi(&mut state, &[2, 3], 'H');
+        csi(&mut state, &[1], 'K');
+        csi(&mut state, &[3, 3], 'H');
+        csi(&mut state, &[2], 'K');
+        assert_eq!(text(&state), vec!["aa   ", "   bb", "     "]);
+    }
+
+    #[test]
+    fn ed_erases_below_and_above() {
+        let mut state = filled(3, 3);
+        csi(&mut state, &[2, 2], 'H');
+        csi(&mut state, &[0], 'J');
+        assert_eq!(text(&state), vec!["aaa", "b  ", "   "]);
+
+        let mut state = filled(3, 3);
+        csi(&mut state, &[2, 2], 'H');
+        csi(&mut state, &[1], 'J');
+        assert_eq!(text(&state), vec!["   ", "  b", "ccc"]);
+    }
+
+    #[test]
+    fn ed_2_clears_screen_and_ed_3_clears_scrollback() {
+        let mut state = filled(3, 2);
+        feed(&mut state, "\r\nddd");
+        assert_eq!(state.scrollback.len(), 1);
+
+        csi(&mut state, &[2], 'J');
+        assert_eq!(text(&state), vec!["   ", "   "]);
+        assert_eq!(state.scrollback.len(), 1);
+
+        csi(&mut state, &[3], 'J');
+        assert!(state.scrollback.is_empty());
+    }
+
+    #[test]
+    fn erase_uses_the_current_background_colour() {
+        let mut state = filled(3, 1);
+        csi(&mut state, &[44], 'm');
+        csi(&mut state, &[2], 'K');
+        assert!(state.lines[0]
+            .iter()
+            .all(|cell| cell.c == ' ' && cell.bg == Color::Indexed(4)));
+    }
+
+    #[test]
+    fn ich_and_dch_shift_the_rest_of_the_line() {
+        let mut state = State::new(6, 1, 100);
+        feed(&mut state, "abcdef");
+        csi(&mut state, &[1, 2], 'H');
+        csi(&mut state, &[2], '@');
+        assert_eq!(text(&state), vec!["a  bcd"]);
+        csi(&mut state, &[3], 'P');
+        assert_eq!(text(&state), vec!["acd   "]);
+    }
+
+    #[test]
+    fn irm_inserts_printed_characters() {
+        let mut state = State::new(5, 1, 100);
+        feed(&mut state, "abc");
+        csi(&mut state, &[1, 1], 'H');
+        csi(&mut state, &[4], 'h');
+        feed(&mut state, "xy");
+        assert_eq!(text(&state), vec!["xyabc"]);
+        csi(&mut state, &[4], 'l');
+        feed(&mut state, "z");
+        assert_eq!(text(&state), vec!["xyzbc"]);
+    }
+
+    #[test]
+    fn il_and_dl_stay_inside_the_scroll_region() {
+        let mut state = filled(2, 4);
+        state.scroll_top = 1;
+        state.scroll_bottom = 3;
+        csi(&mut state, &[2, 2], 'H');
+        csi(&mut state, &[1], 'L');
+        assert_eq!(text(&state), vec!["aa", "  ", "bb", "dd"]);
+        assert_eq!(state.cursor.x, 0);
+        csi(&mut state, &[5], 'M');
+        assert_eq!(text(&state), vec!["aa", "  ", "  ", "dd"]);
+        csi(&mut state, &[4, 1], 'H');
+        csi(&mut state, &[1], 'L');
+        assert_eq!(text(&state), vec!["aa", "  ", "  ", "dd"]);
+    }
+
+    fn esc(state: &mut State, byte: u8) {
+        update(state, &Action::DispatchESC(vec![], false, byte));
+    }
+
+    #[test]
+    fn linefeed_scrolls_only_the_scroll_region() {
+        let mut state = filled(2, 4);
+        csi(&mut state, &[2, 3], 'r');
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 0));
+        csi(&mut state, &[3, 1], 'H');
+        feed(&mut state, "\n");
+        assert_eq!(text(&state), vec!["aa", "cc", "  ", "dd"]);
+        assert_eq!(state.cursor.y, 2);
+        assert!(state.scrollback.is_empty());
+    }
+
+    #[test]
+    fn full_screen_scrolls_enter_scrollback() {
+        let mut state = filled(2, 3);
+        csi(&mut state, &[2], 'S');
+        assert_eq!(text(&state), vec!["cc", "  ", "  "]);
+        assert_eq!(state.scrollback.len(), 2);
+        csi(&mut state, &[1], 'T');
+        assert_eq!(text(&state), vec!["  ", "cc", "  "]);
+    }
+
+    #[test]
+    fn scrolling_the_viewport_stops_at_the_ends_of_the_history() {
+        let mut state = State::new(3, 2, 100);
+        feed(&mut state, "a\r\nb\r\nc\r\nd");
+        assert_eq!(state.scrollback.len(), 2);
+        update(&mut state, &Action::ScrollLines(-1));
+        assert_eq!(state.viewport_offset, 0);
+        update(&mut state, &Action::ScrollLines(1));
+        assert_eq!(state.viewport_offset, 1);
+        update(&mut state, &Action::ScrollPages(5));
+        assert_eq!(state.viewport_offset, 2);
+        let visible: Vec<_> = state.visible_lines().map(|line| line[0].c).collect();
+        assert_eq!(visible, vec!['a', 'b']);
+        update(&mut state, &Action::ScrollPages(-5));
+        assert_eq!(state.viewport_offset, 0);
+
+        // The alternate screen has no history to scroll through.
+        decset(&mut state, 1049, true);
+        update(&mut state, &Action::ScrollLines(1));
+        assert_eq!(state.viewport_offset, 0);
+    }
+
+    #[test]
+    fn viewport_stays_on_its_content_while_output_arrives() {
+        let mut state = State::new(3, 2, 4);
+        feed(&mut state, "a\r\nb\r\nc");
+        update(&mut state, &Action::ScrollLines(1));
+        let top = |state: &State| state.visible_lines().next().unwrap()[0].c;
+        assert_eq!(top(&state), 'a');
+        feed(&mut state, "\r\nd\r\ne");
+        assert_eq!(state.viewport_offset, 3);
+        assert_eq!(top(&state), 'a');
+        // Once the history is full, the oldest lines are dropped and the
+        // viewport stays at the top.
+        feed(&mut state, "\r\nf\r\ng");
+        assert_eq!(state.scrollback.len(), 4);
+        assert_eq!(state.viewport_offset, 4);
+        assert_eq!(top(&state), 'b');
+    }
+
+    #[test]
+    fn reverse_index_scrolls_down_at_the_top_margin() {
+        let mut state = filled(2, 3);
+        csi(&mut state, &[2, 3], 'r');
+        csi(&mut state, &[2, 1], 'H');
+        esc(&mut state, b'M');
+        assert_eq!(text(&state), vec!["aa", "  ", "bb"]);
+        assert_eq!(state.cursor.y, 1);
+        csi(&mut state, &[1, 2], 'H');
+        esc(&mut state, b'M');
+        assert_eq!(text(&state), vec!["aa", "  ", "bb"]);
+        assert_eq!(state.cursor.y, 0);
+    }
+
+    #[test]
+    fn index_and_next_line() {
+        let mut state = filled(2, 2);
+        csi(&mut state, &[1, 2], 'H');
+        esc(&mut state, b'D');
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+        esc(&mut state, b'E');
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 1));
+        assert_eq!(text(&state), vec!["bb", "  "]);
+    }
+
+    fn decset(state: &mut State, mode: i64, enable: bool) {
+        let c = if enable { 'h' } else { 'l' };
+        update(
+            state,
+            &Action::DispatchCSI(vec![vec![mode]], vec![b'?'], false, c),
+        );
+    }
+
+    #[test]
+    fn mode_1049_switches_to_a_cleared_alternate_screen_and_back() {
+        let mut state = filled(2, 2);
+        csi(&mut state, &[1, 2], 'H');
+        decset(&mut state, 1049, true);
+        assert!(state.alternate_screen);
+        assert_eq!(text(&state), vec!["  ", "  "]);
+        feed(&mut state, "\r\nxx\r\nyy");
+        assert!(state.scrollback.is_empty());
+
+        decset(&mut state, 1049, false);
+        assert!(!state.alternate_screen);
+        assert_eq!(text(&state), vec!["aa", "bb"]);
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 0));
+    }
+
+    #[test]
+    fn mode_47_keeps_and_1047_clears_the_alternate_screen() {
+        let mut state = filled(2, 1);
+        decset(&mut state, 47, true);
+        feed(&mut state, "\rxx");
+        decset(&mut state, 47, false);
+        decset(&mut state, 47, true);
+        assert_eq!(text(&state), vec!["xx"]);
+        decset(&mut state, 1047, false);
+        decset(&mut state, 1047, true);
+        assert_eq!(text(&state), vec!["  "]);
+    }
+
+    #[test]
+    fn cursor_movement_clamps_instead_of_panicking() {
+        let mut state = State::new(4, 3, 100);
+        update(&mut state, &Action::Execute(8));
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 0));
+        csi(&mut state, &[9], 'D');
+        csi(&mut state, &[9], 'A');
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 0));
+        csi(&mut state, &[9], 'C');
+        csi(&mut state, &[9], 'B');
+        assert_eq!((state.cursor.x, state.cursor.y), (3, 2));
+        csi(&mut state, &[99, 99], 'f');
+        assert_eq!((state.cursor.x, state.cursor.y), (3, 2));
+    }
+
+    #[test]
+    fn zero_parameters_count_as_one() {
+        let mut state = State::new(4, 4, 100);
+        csi(&mut state, &[0], 'B');
+        csi(&mut state, &[0], 'C');
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+        csi(&mut state, &[0], 'E');
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 2));
+        csi(&mut state, &[3], '`');
+        csi(&mut state, &[0], 'F');
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 1));
+        csi(&mut state, &[4], 'd');
+        csi(&mut state, &[2], 'G');
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 3));
+    }
+
+    #[test]
+    fn vertical_movement_stops_at_the_scroll_margins() {
+        let mut state = State::new(4, 6, 100);
+        csi(&mut state, &[2, 4], 'r');
+        csi(&mut state, &[3, 1], 'H');
+        csi(&mut state, &[9], 'A');
+        assert_eq!(state.cursor.y, 1);
+        csi(&mut state, &[9], 'B');
+        assert_eq!(state.cursor.y, 3);
+        csi(&mut state, &[6, 1], 'H');
+        csi(&mut state, &[1], 'A');
+        assert_eq!(state.cursor.y, 4);
+    }
+
+    #[test]
+    fn origin_mode_addresses_relative_to_the_scroll_region() {
+        let mut state = State::new(4, 6, 100);
+        csi(&mut state, &[2, 4], 'r');
+        decset(&mut state, 6, true);
+        assert_eq!((state.cursor.x, state.cursor.y), (0, 1));
+        csi(&mut state, &[2, 2], 'H');
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 2));
+        csi(&mut state, &[9], 'd');
+        assert_eq!(state.cursor.y, 3);
+    }
+
+    #[test]
+    fn decsc_saves_position_attributes_and_pending_wrap() {
+        let mut state = State::new(3, 3, 100);
+        csi(&mut state, &[1], 'm');
+        feed(&mut state, "abc");
+        esc(&mut state, b'7');
+        csi(&mut state, &[0], 'm');
+        csi(&mut state, &[3, 1], 'H');
+        esc(&mut state, b'8');
+        assert_eq!((state.cursor.x, state.cursor.y), (2, 0));
+        assert!(state.pen.attrs.bold);
+        feed(&mut state, "d");
+        assert_eq!(text(&state), vec!["abc", "d  ", "   "]);
+    }
+
+    #[test]
+    fn saved_cursors_are_kept_per_screen() {
+        let mut state = State::new(3, 3, 100);
+        csi(&mut state, &[2, 2], 'H');
+        csi(&mut state, &[], 's');
+        decset(&mut state, 47, true);
+        csi(&mut state, &[3, 3], 'H');
+        csi(&mut state, &[], 's');
+        decset(&mut state, 47, false);
+        csi(&mut state, &[], 'u');
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+        decset(&mut state, 47, true);
+        csi(&mut state, &[], 'u');
+        assert_eq!((state.cursor.x, state.cursor.y), (2, 2));
+    }
+
+    #[test]
+    fn tabs_stop_every_eight_columns_by_default() {
+        let mut state = State::new(20, 1, 100);
+        update(&mut state, &Action::Execute(9));
+        assert_eq!(state.cursor.x, 8);
+        csi(&mut state, &[5], 'I');
+        assert_eq!(state.cursor.x, 19);
+        csi(&mut state, &[1], 'Z');
+        assert_eq!(state.cursor.x, 16);
+        csi(&mut state, &[9], 'Z');
+        assert_eq!(state.cursor.x, 0);
+    }
+
+    #[test]
+    fn tab_stops_can_be_set_and_cleared() {
+        let mut state = State::new(20, 1, 100);
+        csi(&mut state, &[3], 'g');
+        csi(&mut state, &[1, 4], 'H');
+        esc(&mut state, b'H');
+        csi(&mut state, &[1, 1], 'H');
+        update(&mut state, &Action::Execute(9));
+        assert_eq!(state.cursor.x, 3);
+        csi(&mut state, &[0], 'g');
+        csi(&mut state, &[1, 1], 'H');
+        update(&mut state, &Action::Execute(9));
+        assert_eq!(state.cursor.x, 19);
+
+        update(&mut state, &Action::Resize(30, 1));
+        csi(&mut state, &[1, 1], 'H');
+        update(&mut state, &Action::Execute(9));
+        assert_eq!(state.cursor.x, 8);
+    }
+
+    #[test]
+    fn resize_keeps_the_cursor_line_visible() {
+        let mut state = filled(3, 4);
+        update(&mut state, &Action::Resize(2, 2));
+        assert_eq!(text(&state), vec!["cc", "dd"]);
+        assert_eq!(state.scrollback.len(), 2);
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+        update(&mut state, &Action::Resize(3, 3));
+        assert_eq!(text(&state), vec!["cc ", "dd ", "   "]);
+    }
+
+    #[test]
+    fn resize_on_the_alternate_screen_keeps_the_primary_cursor_line() {
+        let mut state = filled(3, 4);
+        csi(&mut state, &[3, 2], 'H');
+        decset(&mut state, 1049, true);
+        update(&mut state, &Action::Resize(2, 2));
+        assert_eq!(state.scrollback.len(), 1);
+        assert_eq!(state.saved_cursors[0].position.y, 1);
+
+        decset(&mut state, 1049, false);
+        assert_eq!(text(&state), vec!["bb", "cc"]);
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+    }
+
+    fn esc_with(state: &mut State, intermediate: u8, byte: u8) {
+        update(state, &Action::DispatchESC(vec![intermediate], false, byte));
+    }
+
+    #[test]
+    fn dec_special_graphics_draws_lines() {
+        let mut state = State::new(6, 1, 100);
+        esc_with(&mut state, b'(', b'0');
+        feed(&mut state, "lqk");
+        esc_with(&mut state, b'(', b'B');
+        feed(&mut state, "lqk");
+        assert_eq!(text(&state), vec!["┌─┐lqk"]);
+    }
+
+    #[test]
+    fn shift_out_invokes_g1_until_shift_in() {
+        let mut state = State::new(4, 1, 100);
+        esc_with(&mut state, b')', b'0');
+        update(&mut state, &Action::Execute(14));
+        feed(&mut state, "xq");
+        update(&mut state, &Action::Execute(15));
+        feed(&mut state, "xq");
+        assert_eq!(text(&state), vec!["│─xq"]);
+    }
+
+    #[test]
+    fn single_shift_applies_to_one_character() {
+        let mut state = State::new(3, 1, 100);
+        esc_with(&mut state, b'+', b'A');
+        esc(&mut state, b'O');
+        feed(&mut state, "##");
+        esc_with(&mut state, b'*', b'0');
+        esc(&mut state, b'N');
+        feed(&mut state, "a");
+        assert_eq!(text(&state), vec!["£#▒"]);
+    }
+
+    #[test]
+    fn decset_updates_the_mode_registry() {
+        let mut state = State::new(3, 2, 100);
+        assert!(state.modes.get(Mode::Autowrap));
+        decset(&mut state, 7, false);
+        decset(&mut state, 2004, true);
+        assert!(!state.modes.get(Mode::Autowrap));
+        assert!(state.modes.get(Mode::BracketedPaste));
+        feed(&mut state, "abcd");
+        assert_eq!(text(&state), vec!["abd", "   "]);
+    }
+
+    #[test]
+    fn synchronized_output_holds_rendering_for_a_limited_time() {
+        let mut state = State::new(3, 2, 100);
+        assert_eq!(state.synchronized_output_deadline(), None);
+        decset(&mut state, 2026, true);
+        let since = state.synchronized_since.unwrap();
+        // Turning it on again does not extend the deadline.
+        decset(&mut state, 2026, true);
+        let deadline = state.synchronized_output_deadline().unwrap();
+        assert_eq!(deadline - since, std::time::Duration::from_millis(150));
+        decset(&mut state, 2026, false);
+        assert_eq!(state.synchronized_since, None);
+        assert_eq!(state.synchronized_output_deadline(), None);
+    }
+
+    #[test]
+    fn decrqm_reports_modes_from_the_registry() {
+        let mut state = State::new(3, 2, 100);
+        decset(&mut state, 1004, true);
+        for &mode in &[1004, 25, 1, 1049, 31337] {
+            let params = vec![vec![mode]];
+            update(
+                &mut state,
+                &Action::DispatchCSI(params, vec![b'?', b'$'], false, 'p'),
+            );
+        }
+        csi(&mut state, &[4], 'h');
+        update(
+            &mut state,
+            &Action::DispatchCSI(vec![vec![4]], vec![b'$'], false, 'p'),
+        );
+        assert_eq!(
+            String::from_utf8(state.responses).unwrap(),
+            "\x1b[?1004;1$y\x1b[?25;1$y\x1b[?1;2$y\x1b[?1049;2$y\x1b[?31337;0$y\x1b[4;1$y"
+        );
+    }
+
+    fn responses(state: &mut State) -> String {
+        let responses = String::from_utf8(state.responses.clone()).unwrap();
+        update(state, &Action::ResponsesSent());
+        responses
+    }
+
+    #[test]
+    fn device_attributes_are_reported() {
+        let mut state = State::new(3, 2, 100);
+        csi(&mut state, &[], 'c');
+        assert_eq!(responses(&mut state), "\x1b[?62;4;22c");
+        update(
+            &mut state,
+            &Action::DispatchCSI(vec![], vec![b'>'], false, 'c'),
+        );
+        assert_eq!(responses(&mut state), "\x1b[>1;10;0c");
+        assert!(state.responses.is_empty());
+    }
+
+    #[test]
+    fn device_status_and_cursor_position_are_reported() {
+        let mut state = State::new(10, 10, 100);
+        csi(&mut state, &[5], 'n');
+        assert_eq!(responses(&mut state), "\x1b[0n");
+        csi(&mut state, &[3, 7], 'H');
+        csi(&mut state, &[6], 'n');
+        assert_eq!(responses(&mut state), "\x1b[3;7R");
+        update(
+            &mut state,
+            &Action::DispatchCSI(vec![vec![6]], vec![b'?'], false, 'n'),
+        );
+        assert_eq!(responses(&mut state), "\x1b[?3;7R");
+    }
+
+    #[test]
+    fn cursor_position_report_is_relative_to_the_origin() {
+        let mut state = State::new(10, 10, 100);
+        csi(&mut state, &[4, 8], 'r');
+        decset(&mut state, 6, true);
+        csi(&mut state, &[2, 3], 'H');
+        csi(&mut state, &[6], 'n');
+        assert_eq!(responses(&mut state), "\x1b[2;3R");
+    }
+
+    #[test]
+    fn xtversion_reports_name_and_version() {
+        let mut state = State::new(3, 2, 100);
+        update(
+            &mut state,
+            &Action::DispatchCSI(vec![], vec![b'>'], false, 'q'),
+        );
+        assert_eq!(
+            responses(&mut state),
+            format!("\x1bP>|greentty({})\x1b\\", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    fn osc_with(state: &mut State, params: &[&str]) {
+        let params = params.iter().map(|p| p.as_bytes().to_vec()).collect();
+        update(state, &Action::DispatchOSC(params, true));
+    }
+
+    #[test]
+    fn osc_sets_title_and_icon_name() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["0", "vim", "main.rs"]);
+        assert_eq!(state.title, "vim;main.rs");
+        assert_eq!(state.icon_name, "vim;main.rs");
+        osc_with(&mut state, &["2", "make"]);
+        osc_with(&mut state, &["1", "icon"]);
+        assert_eq!(state.title, "make");
+        assert_eq!(state.icon_name, "icon");
+    }
+
+    #[test]
+    fn xtwinops_pushes_and_pops_titles() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["0", "shell"]);
+        csi(&mut state, &[22, 0], 't');
+        osc_with(&mut state, &["0", "vim"]);
+        csi(&mut state, &[22, 2], 't');
+        osc_with(&mut state, &["2", "less"]);
+
+        csi(&mut state, &[23, 2], 't');
+        assert_eq!((&state.title[..], &state.icon_name[..]), ("vim", "vim"));
+        csi(&mut state, &[23, 0], 't');
+        assert_eq!((&state.title[..], &state.icon_name[..]), ("shell", "shell"));
+        csi(&mut state, &[23, 0], 't');
+        assert_eq!(state.title, "shell");
+    }
+
+    #[test]
+    fn osc_8_attaches_hyperlinks_to_printed_cells() {
+        let mut state = State::new(6, 1, 100);
+        osc_with(&mut state, &["8", "id=a", "https://example.com/?q=1;2"]);
+        feed(&mut state, "ab");
+        osc_with(&mut state, &["8", "", ""]);
+        feed(&mut state, "c");
+        osc_with(&mut state, &["8", "id=a", "https://example.com/?q=1;2"]);
+        feed(&mut state, "d");
+
+        let links: Vec<_> = state.lines[0].iter().map(|cell| cell.hyperlink).collect();
+        assert_eq!(links, vec![Some(0), Some(0), None, Some(0), None, None]);
+        assert_eq!(state.hyperlinks[&0].id, Some("a".to_string()));
+        assert_eq!(state.hyperlinks[&0].uri, "https://example.com/?q=1;2");
+    }
+
+    #[test]
+    fn hyperlinks_that_left_the_scrollback_are_dropped() {
+        let mut state = State::new(4, 2, 10);
+        for n in 0..1000 {
+            osc_with(&mut state, &["8", "", &format!("file:///{}", n)]);
+            feed(&mut state, "x\r\n");
+        }
+        osc_with(&mut state, &["8", "", ""]);
+        assert!(state.hyperlinks.len() <= 256);
+        assert_eq!(state.hyperlinks.len(), state.hyperlink_numbers.len());
+        // The links still on screen or in the scrollback are kept.
+        for line in state.scrollback.iter().chain(&state.lines) {
+            if let Some(number) = line[0].hyperlink {
+                assert!(state.hyperlinks.contains_key(&number));
+            }
+        }
+        csi(&mut state, &[3], 'J');
+        assert_eq!(state.hyperlinks.len(), 1);
+    }
+
+    #[test]
+    fn hyperlinks_that_were_erased_or_scrolled_away_are_dropped() {
+        let mut state = State::new(4, 2, 10);
+        decset(&mut state, 1049, true);
+        for n in 0..1000 {
+            osc_with(&mut state, &["8", "", &format!("file:///{}", n)]);
+            feed(&mut state, "x\r\n");
+        }
+        assert!(state.hyperlinks.len() <= 256);
+        decset(&mut state, 1049, false);
+        for n in 0..1000 {
+            osc_with(&mut state, &["8", "", &format!("file:///{}", n)]);
+            feed(&mut state, "x\r");
+            csi(&mut state, &[2], 'K');
+        }
+        assert!(state.hyperlinks.len() <= 256);
+    }
+
+    #[test]
+    fn hovered_hyperlink_follows_the_mouse() {
+        let mut state = State::new(6, 2, 100);
+        update(&mut state, &Action::SetCellSize(10.0, 20.0, 2.0));
+        feed(&mut state, "x");
+        osc_with(&mut state, &["8", "", "file:///tmp"]);
+        feed(&mut state, "link");
+        update(&mut state, &Action::MouseMoved(12.0, 5.0));
+        assert_eq!(state.hovered_hyperlink(), Some(0));
+        update(&mut state, &Action::MouseMoved(2.0, 5.0));
+        assert_eq!(state.hovered_hyperlink(), None);
+        update(&mut state, &Action::MouseMoved(12.0, 5.0));
+        update(&mut state, &Action::MouseLeft());
+        assert_eq!(state.hovered_hyperlink(), None);
+    }
+
+    #[test]
+    fn osc_4_sets_and_queries_palette_colours() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["4", "1", "rgb:12/34/56", "2", "#abc"]);
+        assert_eq!(state.palette.colors[1], (0x12, 0x34, 0x56));
+        assert_eq!(state.palette.colors[2], (0xaa, 0xbb, 0xcc));
+        osc_with(&mut state, &["4", "1", "?"]);
+        assert_eq!(responses(&mut state), "\x1b]4;1;rgb:1212/3434/5656\x07");
+        osc_with(&mut state, &["104", "1"]);
+        assert_eq!(state.palette.colors[1], (0xcd, 0x00, 0x00));
+        assert_eq!(state.palette.colors[2], (0xaa, 0xbb, 0xcc));
+        osc_with(&mut state, &["104"]);
+        assert_eq!(state.palette.colors[2], (0x00, 0xcd, 0x00));
+    }
+
+    #[test]
+    fn osc_10_to_12_set_and_query_dynamic_colours() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["11", "rgb:ffff/ffff/ffff"]);
+        osc_with(&mut state, &["10", "?", "?", "?"]);
+        assert_eq!(
+            responses(&mut state),
+            "\x1b]10;rgb:ffff/ffff/ffff\x07\
+             \x1b]11;rgb:ffff/ffff/ffff\x07\
+             \x1b]12;rgb:ffff/ffff/ffff\x07"
+        );
+        osc_with(&mut state, &["12", "#f00"]);
+        assert_eq!(state.palette.cursor, Some((0xff, 0x00, 0x00)));
+        osc_with(&mut state, &["111"]);
+        osc_with(&mut state, &["112"]);
+        assert_eq!(state.palette.background, (0x00, 0x00, 0x00));
+        assert_eq!(state.palette.cursor, None);
+    }
+
+    #[test]
+    fn osc_133_marks_lines_and_records_exit_status() {
+        let mut state = State::new(5, 3, 100);
+        osc_with(&mut state, &["133", "A"]);
+        feed(&mut state, "$ ");
+        osc_with(&mut state, &["133", "B"]);
+        feed(&mut state, "ls\r\n");
+        osc_with(&mut state, &["133", "C"]);
+        feed(&mut state, "a\r\nb\r\n");
+        osc_with(&mut state, &["133", "D", "1"]);
+        osc_with(&mut state, &["133", "A"]);
+        feed(&mut state, "$ ");
+
+        let marks: Vec<_> = state.scrollback.iter().map(|line| line.marks).collect();
+        assert_eq!(marks.len(), 1);
+        assert!(marks[0].prompt && marks[0].command);
+        assert_eq!(marks[0].exit_status, Some(1));
+        assert!(state.lines[0].marks.output);
+        assert!(state.lines[2].marks.prompt);
+        assert_eq!(state.lines[2].marks.exit_status, None);
+
+        update(&mut state, &Action::SelectLastOutput());
+        assert_eq!(state.selection, Some(1..=2));
+        assert_eq!(state.visible_selection(), Some(0..=1));
+        update(&mut state, &Action::JumpToPrompt(-1));
+        assert_eq!(state.viewport_offset, 1);
+        assert_eq!(state.visible_selection(), Some(1..=2));
+        update(&mut state, &Action::JumpToPrompt(1));
+        assert_eq!(state.viewport_offset, 0);
+    }
+
+    #[test]
+    fn erased_prompts_are_not_jumped_to() {
+        let mut state = State::new(5, 3, 100);
+        osc_with(&mut state, &["133", "A"]);
+        feed(&mut state, "$ 1\r\na\r\nb\r\n");
+        osc_with(&mut state, &["133", "A"]);
+        feed(&mut state, "$ 2\r\n");
+        csi(&mut state, &[2], 'J');
+        assert!(state.lines.iter().all(|line| !line.marks.prompt));
+
+        update(&mut state, &Action::JumpToPrompt(-1));
+        assert_eq!(state.viewport_offset, 2);
+        update(&mut state, &Action::JumpToPrompt(1));
+        assert_eq!(state.viewport_offset, 2);
+    }
+
+    #[test]
+    fn clearing_the_scrollback_moves_the_selection_with_the_lines() {
+        let mut state = filled(2, 4);
+        update(&mut state, &Action::Resize(2, 2));
+        assert_eq!(state.scrollback.len(), 2);
+        state.selection = Some(1..=3);
+        csi(&mut state, &[3], 'J');
+        assert_eq!(state.selection, Some(0..=1));
+
+        feed(&mut state, "\r\n\r\n");
+        state.selection = Some(0..=1);
+        csi(&mut state, &[3], 'J');
+        assert_eq!(state.selection, None);
+    }
+
+    #[test]
+    fn osc_7_sets_the_working_directory() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["7", "file://host/home/user/my%20dir"]);
+        assert_eq!(state.cwd, Some("/home/user/my dir".to_string()));
+        osc_with(&mut state, &["7", "not a url"]);
+        assert_eq!(state.cwd, Some("/home/user/my dir".to_string()));
+    }
+
+    #[test]
+    fn bel_and_osc_9_and_777_queue_notifications() {
+        let mut state = State::new(3, 2, 100);
+        update(&mut state, &Action::Execute(7));
+        assert!(state.bell);
+        assert!(state.flashing());
+        osc_with(&mut state, &["2", "make"]);
+        osc_with(&mut state, &["9", "build done"]);
+        osc_with(&mut state, &["9", "4", "1", "50"]);
+        osc_with(
+            &mut state,
+            &["777", "notify", "tests", "all passed; 0 failed"],
+        );
+        assert_eq!(
+            state.notifications,
+            vec![
+                Notification {
+                    title: "make".to_string(),
+                    body: "build done".to_string(),
+                },
+                Notification {
+                    title: "tests".to_string(),
+                    body: "all passed; 0 failed".to_string(),
+                },
+            ]
+        );
+        update(&mut state, &Action::NotificationsHandled());
+        assert!(!state.bell);
+        assert!(state.notifications.is_empty());
+    }
+
+    fn dcs_with(state: &mut State, intermediates: &[u8], c: char, data: &str) {
+        let action = Action::DispatchDCS(vec![], intermediates.to_vec(), false, c, data.into());
+        update(state, &action);
+    }
+
+    #[test]
+    fn decrqss_reports_sgr_margins_and_cursor_style() {
+        let mut state = State::new(3, 5, 100);
+        csi(&mut state, &[1, 4, 31, 48, 5, 200], 'm');
+        dcs_with(&mut state, b"$", 'q', "m");
+        assert_eq!(responses(&mut state), "\x1bP1$r0;1;4;31;48;5;200m\x1b\\");
+        csi(&mut state, &[2, 4], 'r');
+        dcs_with(&mut state, b"$", 'q', "r");
+        assert_eq!(responses(&mut state), "\x1bP1$r2;4r\x1b\\");
+        update(
+            &mut state,
+            &Action::DispatchCSI(vec![vec![6]], vec![b' '], false, 'q'),
+        );
+        dcs_with(&mut state, b"$", 'q', " q");
+        assert_eq!(responses(&mut state), "\x1bP1$r6 q\x1b\\");
+        dcs_with(&mut state, b"$", 'q', "x");
+        assert_eq!(responses(&mut state), "\x1bP0$r\x1b\\");
+    }
+
+    #[test]
+    fn xtgettcap_answers_from_the_builtin_terminfo() {
+        let mut state = State::new(3, 2, 100);
+        // TN;colors;am;xyz
+        dcs_with(&mut state, b"+", 'q', "544E;636F6C6F7273;616D;78797A");
+        assert_eq!(
+            responses(&mut state),
+            "\x1bP1+r544E=787465726D2D323536636F6C6F72\x1b\\\
+             \x1bP1+r636F6C6F7273=323536\x1b\\\
+             \x1bP1+r616D\x1b\\\
+             \x1bP0+r78797A\x1b\\"
+        );
+    }
+
+    #[test]
+    fn sixel_images_are_placed_at_the_cursor_and_scroll_with_text() {
+        let mut state = State::new(4, 3, 100);
+        feed(&mut state, "a");
+        let action = Action::DispatchDCS(
+            vec![vec![0], vec![1]],
+            vec![],
+            false,
+            'q',
+            b"\"1;1;2;12#1;2;100;0;0!2~-#2;2;0;0;100~".to_vec(),
+        );
+        update(&mut state, &action);
+        assert_eq!(state.images.len(), 1);
+        let image = &state.images[0];
+        assert_eq!((image.row, image.col), (0, 1));
+        assert_eq!((image.bitmap.width, image.bitmap.height), (2, 12));
+        let pixel = |x: usize, y: usize| {
+            let i = (y * 2 + x) * 4;
+            image.bitmap.pixels[i..i + 4].to_vec()
+        };
+        assert_eq!(pixel(1, 5), [255, 0, 0, 255]);
+        assert_eq!(pixel(0, 6), [0, 0, 255, 255]);
+        assert_eq!(pixel(1, 6), [0, 0, 0, 0]);
+        // The cursor moves below the image, keeping its column.
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+
+        feed(&mut state, "\n\n\n");
+        assert_eq!(state.scrollback.len(), 2);
+        assert_eq!(state.images[0].row, 0);
+        assert_eq!(state.viewport_top(), 2);
+        csi(&mut state, &[3], 'J');
+        assert!(state.images.is_empty());
+    }
+
+    #[test]
+    fn sixel_images_without_a_size_grow_as_they_are_drawn() {
+        let mut state = State::new(4, 3, 100);
+        let action = Action::DispatchDCS(vec![], vec![], false, 'q', b"#1~-!3~-~".to_vec());
+        update(&mut state, &action);
+        let image = &state.images[0];
+        assert_eq!((image.bitmap.width, image.bitmap.height), (3, 18));
+        let pixel = |x: usize, y: usize| {
+            let i = (y * 3 + x) * 4;
+            image.bitmap.pixels[i..i + 4].to_vec()
+        };
+        // Pixels that were not drawn take colour register 0.
+        assert_eq!(pixel(0, 0), [51, 51, 204, 255]);
+        assert_eq!(pixel(2, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(2, 11), [51, 51, 204, 255]);
+        assert_eq!(pixel(0, 17), [51, 51, 204, 255]);
+        assert_eq!(pixel(1, 17), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn clearing_the_scrollback_keeps_alternate_screen_images_in_place() {
+        let mut state = State::new(4, 3, 100);
+        feed(&mut state, "a\nb\nc\nd\n");
+        assert_eq!(state.scrollback.len(), 2);
+        decset(&mut state, 1049, true);
+        csi(&mut state, &[2, 1], 'H');
+        let sixel = Action::DispatchDCS(vec![], vec![], false, 'q', b"~".to_vec());
+        update(&mut state, &sixel);
+        assert_eq!(state.images[0].row, 3);
+
+        csi(&mut state, &[3], 'J');
+        assert_eq!(state.images.len(), 1);
+        assert_eq!(state.images[0].row, 1);
+        assert_eq!(state.visible_images().count(), 1);
+    }
+
+    fn kitty_with(state: &mut State, control: &str, payload: &[u8]) {
+        let data = format!("G{};{}", control, base64::encode(payload));
+        update(state, &Action::DispatchAPC(data.into_bytes()));
+    }
+
+    #[test]
+    fn kitty_images_are_transmitted_and_placed() {
+        let mut state = State::new(10, 5, 100);
+        feed(&mut state, "a");
+        let pixels = [1, 2, 3, 255, 4, 5, 6, 255, 7, 8, 9, 255, 10, 11, 12, 255];
+        kitty_with(&mut state, "a=T,f=32,s=2,v=2,i=5,c=3,r=2,X=4,z=-1", &pixels);
+        assert_eq!(responses(&mut state), "\x1b_Gi=5;OK\x1b\\");
+        assert_eq!(state.images.len(), 1);
+        let image = &state.images[0];
+        assert_eq!((image.row, image.col), (0, 1));
+        assert_eq!((image.width, image.height), (30.0, 40.0));
+        assert_eq!((image.offset, image.z_index), ((4.0, 0.0), -1));
+        assert_eq!(image.bitmap.pixels, pixels);
+        // The cursor moves after the bottom right corner.
+        assert_eq!((state.cursor.x, state.cursor.y), (5, 1));
+
+        // The same placement id replaces the placement, and C=1 keeps the
+        // cursor where it is.
+        kitty_with(&mut state, "a=p,i=5,p=1,w=1,h=1,C=1", b"");
+        kitty_with(&mut state, "a=p,i=5,p=1,x=1,y=1,c=1,C=1", b"");
+        assert_eq!(
+            responses(&mut state),
+            "\x1b_Gi=5,p=1;OK\x1b\\\x1b_Gi=5,p=1;OK\x1b\\"
+        );
+        assert_eq!(state.images.len(), 2);
+        let image = &state.images[1];
+        assert_eq!(image.bitmap.pixels, [10, 11, 12, 255]);
+        assert_eq!((image.width, image.height), (10.0, 10.0));
+        assert_eq!((state.cursor.x, state.cursor.y), (5, 1));
+    }
+
+    #[test]
+    fn kitty_images_can_be_sent_in_chunks_as_png() {
+        let mut png = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut png, 1, 2);
+            encoder.set_color(png::ColorType::Rgb);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[255, 0, 0, 0, 0, 255]).unwrap();
+        }
+        let payload = base64::encode(&png);
+        let (first, rest) = payload.split_at(8);
+        let mut state = State::new(10, 5, 100);
+        let chunk = format!("Ga=t,f=100,I=7,m=1;{}", first);
+        update(&mut state, &Action::DispatchAPC(chunk.into_bytes()));
+        assert_eq!(responses(&mut state), "");
+        let chunk = format!("Gm=0;{}", rest);
+        update(&mut state, &Action::DispatchAPC(chunk.into_bytes()));
+        assert_eq!(responses(&mut state), "\x1b_Gi=1,I=7;OK\x1b\\");
+        assert!(state.images.is_empty());
+
+        kitty_with(&mut state, "a=p,I=7,q=1", b"");
+        assert_eq!(responses(&mut state), "");
+        let image = &state.images[0];
+        assert_eq!(image.image_id, 1);
+        assert_eq!(image.bitmap.pixels, [255, 0, 0, 255, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn kitty_queries_and_errors_are_answered() {
+        let mut state = State::new(10, 5, 100);
+        kitty_with(&mut state, "a=q,i=31,s=1,v=1,f=24", &[0, 0, 0]);
+        assert_eq!(responses(&mut state), "\x1b_Gi=31;OK\x1b\\");
+        assert!(state.images.is_empty());
+        kitty_with(&mut state, "a=q,i=31", b"");
+        assert_eq!(
+            responses(&mut state),
+            "\x1b_Gi=31;EINVAL:missing image size\x1b\\"
+        );
+        kitty_with(&mut state, "a=p,i=31", b"");
+        assert_eq!(
+            responses(&mut state),
+            "\x1b_Gi=31;ENOENT:no such image\x1b\\"
+        );
+        kitty_with(&mut state, "a=p,i=31,q=2", b"");
+        // Commands without an id are never answered.
+        kitty_with(&mut state, "a=T,f=32,s=1,v=1", &[0, 0, 0]);
+        assert_eq!(responses(&mut state), "");
+    }
+
+    #[test]
+    fn kitty_temporary_files_are_removed_only_from_the_temporary_directory() {
+        let mut state = State::new(10, 5, 100);
+        let dir = std::env::temp_dir();
+        let temporary = dir.join(format!(
+            "greentty-{}-tty-graphics-protocol",
+            std::process::id()
+        ));
+        let nested = temporary.join("image");
+        std::fs::write(&temporary, [1, 2, 3]).unwrap();
+        kitty_with(
+            &mut state,
+            "a=t,t=t,f=24,s=1,v=1,i=1",
+            temporary.to_str().unwrap().as_bytes(),
+        );
+        assert_eq!(responses(&mut state), "\x1b_Gi=1;OK\x1b\\");
+        assert!(!temporary.exists());
+
+        std::fs::create_dir(&temporary).unwrap();
+        std::fs::write(&nested, [1, 2, 3]).unwrap();
+        kitty_with(
+            &mut state,
+            "a=t,t=t,f=24,s=1,v=1,i=2",
+            nested.to_str().unwrap().as_bytes(),
+        );
+        assert_eq!(responses(&mut state), "\x1b_Gi=2;OK\x1b\\");
+        assert!(nested.exists());
+        std::fs::remove_dir_all(&temporary).unwrap();
+    }
+
+    #[test]
+    fn kitty_images_are_dropped_when_they_take_too_much_memory() {
+        use crate::graphics::Bitmap;
+
+        // 64 MB of pixels each.
+        let mut state = State::new(10, 5, 100);
+        for id in 1..=6 {
+            state.kitty_images.insert(id, 0, Bitmap::new(4096, 4096));
+            state.limit_image_memory();
+        }
+        assert!(state.kitty_images.get(1).is_none());
+        assert!(state.kitty_images.get(2).is_some());
+
+        // Placed pixels count against the same budget, unless they are
+        // shared with a stored image, and stored images go first.
+        kitty_with(&mut state, "a=p,i=6", b"");
+        kitty_with(&mut state, "a=p,i=6,p=1,w=4095", b"");
+        state.kitty_images.insert(7, 0, Bitmap::new(4096, 4096));
+        state.limit_image_memory();
+        assert!(state.kitty_images.get(2).is_none());
+        assert!(state.kitty_images.get(3).is_none());
+        assert!(state.kitty_images.get(4).is_some());
+        assert_eq!(state.images.len(), 2);
+    }
+
+    #[test]
+    fn kitty_placements_are_limited_to_a_few_screens() {
+        let mut state = State::new(10, 5, 100);
+        kitty_with(&mut state, "a=T,f=24,s=1,v=1,i=1,r=4294967295", &[0, 0, 0]);
+        let image = &state.images[0];
+        assert_eq!((image.width, image.height), (400.0, 400.0));
+        assert_eq!(state.cursor.y, 4);
+    }
+
+    #[test]
+    fn kitty_placements_are_deleted() {
+        let mut state = State::new(10, 5, 100);
+        kitty_with(&mut state, "a=t,i=1,s=1,v=1,q=1", &[0, 0, 0, 255]);
+        kitty_with(&mut state, "a=p,i=1,z=3,q=1", b"");
+        kitty_with(&mut state, "a=p,i=1,q=1", b"");
+        kitty_with(&mut state, "a=d,d=z,z=3", b"");
+        assert_eq!(state.images.len(), 1);
+        assert_eq!(state.images[0].col, 1);
+        kitty_with(&mut state, "a=d,d=x,x=2", b"");
+        assert!(state.images.is_empty());
+
+        // Lower case keeps the image data, upper case frees it.
+        kitty_with(&mut state, "a=p,i=1,q=1", b"");
+        kitty_with(&mut state, "a=d,d=i,i=1", b"");
+        assert!(state.images.is_empty());
+        kitty_with(&mut state, "a=p,i=1", b"");
+        assert_eq!(responses(&mut state), "\x1b_Gi=1;OK\x1b\\");
+        kitty_with(&mut state, "a=d,d=I,i=1", b"");
+        assert!(state.images.is_empty());
+        kitty_with(&mut state, "a=p,i=1", b"");
+        assert_eq!(
+            responses(&mut state),
+            "\x1b_Gi=1;ENOENT:no such image\x1b\\"
+        );
+    }
+
+    fn iterm_file(args: &str, image: &[u8]) -> Action {
+        let data = format!("File={}:{}", args, base64::encode(image));
+        let params = std::iter::once(&b"1337"[..])
+            .chain(data.as_bytes().split(|&b| b == b';'))
+            .map(|p| p.to_vec())
+            .collect();
+        Action::DispatchOSC(params, true)
+    }
+
+    #[test]
+    fn iterm_inline_images_are_sized_and_placed() {
+        let mut png = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut png, 2, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        }
+        let mut state = State::new(10, 5, 100);
+        feed(&mut state, "a");
+        update(
+            &mut state,
+            &iterm_file("name=eC5wbmc=;width=4;inline=1", &png),
+        );
+        let image = &state.images[0];
+        assert_eq!(image.bitmap.pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!((image.row, image.col), (0, 1));
+        assert_eq!((image.width, image.height), (40.0, 20.0));
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 1));
+
+        let args = "width=50%;height=30px;preserveAspectRatio=0;inline=1";
+        update(&mut state, &iterm_file(args, &png));
+        let image = &state.images[1];
+        assert_eq!((image.width, image.height), (50.0, 30.0));
+        assert_eq!((state.cursor.x, state.cursor.y), (1, 3));
+
+        // The image is fitted in the box, and an automatic size is kept
+        // within the screen.
+        update(&mut state, &iterm_file("width=4;height=4;inline=1", &png));
+        assert_eq!(
+            (state.images[2].width, state.images[2].height),
+            (40.0, 20.0)
+        );
+        update(&mut state, &iterm_file("height=100px;inline=1", &png));
+        assert_eq!(
+            (state.images[3].width, state.images[3].height),
+            (200.0, 100.0)
+        );
+        update(&mut state, &iterm_file("inline=1", &png));
+        assert_eq!((state.images[4].width, state.images[4].height), (2.0, 1.0));
+
+        // Sizes are kept within a few screens.
+        let args = "height=4000000000;preserveAspectRatio=0;inline=1";
+        update(&mut state, &iterm_file(args, &png));
+        assert_eq!(
+            (state.images[5].width, state.images[5].height),
+            (2.0, 400.0)
+        );
+        assert_eq!(state.cursor.y, 4);
+
+        // Downloads and unknown formats are ignored.
+        update(&mut state, &iterm_file("width=4", &png));
+        update(&mut state, &iterm_file("inline=1", b"not an image"));
+        assert_eq!(state.images.len(), 6);
+    }
+
+    #[test]
+    fn iterm_inline_gifs_show_the_first_frame() {
+        let mut gif = vec![];
+        {
+            let palette = [0, 0, 0, 255, 0, 0];
+            let mut encoder = gif::Encoder::new(&mut gif, 2, 2, &palette).unwrap();
+            let mut frame = gif::Frame {
+                left: 1,
+                width: 1,
+                height: 2,
+                buffer: vec![1, 1].into(),
+                ..Default::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+            frame.buffer = vec![0, 0].into();
+            encoder.write_frame(&frame).unwrap();
+        }
+        let mut state = State::new(10, 5, 100);
+        update(&mut state, &iterm_file("inline=1", &gif));
+        let image = &state.images[0];
+        assert_eq!((image.bitmap.width, image.bitmap.height), (2, 2));
+        assert_eq!(&image.bitmap.pixels[..8], [0, 0, 0, 0, 255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn osc_52_queues_clipboard_requests() {
+        let mut state = State::new(3, 2, 100);
+        osc_with(&mut state, &["52", "c", "aGVsbG8="]);
+        osc_with(&mut state, &["52", "", "?"]);
+        osc_with(&mut state, &["52", "pc", "!!"]);
+        assert_eq!(
+            state.clipboard_requests,
+            vec![
+                clipboard::Request::Set(vec![Selection::Clipboard], "hello".to_string()),
+                clipboard::Request::Query(Selection::Primary, true),
+                clipboard::Request::Set(
+                    vec![Selection::Primary, Selection::Clipboard],
+                    String::new()
+                ),
+            ]
+        );
+        update(&mut state, &Action::ClipboardRequestsHandled());
+        assert!(state.clipboard_requests.is_empty());
+    }
+
+    fn sgr_with(state: &mut State, params: &[&[i64]]) {
+        let params = params.iter().map(|p| p.to_vec()).collect();
+        update(state, &Action::DispatchCSI(params, vec![], false, 'm'));
+    }
+
+    #[test]
+    fn sgr_sets_colours_with_semicolons() {
+        let mut state = State::new(3, 2, 100);
+        csi(&mut state, &[31, 102], 'm');
+        assert_eq!(
+            (state.pen.fg, state.pen.bg),
+            (Color::Indexed(1), Color::Indexed(10))
+        );
+        csi(&mut state, &[38, 5, 200, 48, 2, 1, 2, 300], 'm');
+        assert_eq!(state.pen.fg, Color::Indexed(200));
+        assert_eq!(state.pen.bg, Color::Rgb(1, 2, 255));
+        csi(&mut state, &[39], 'm');
+        assert_eq!(
+            (state.pen.fg, state.pen.bg),
+            (Color::Default, Color::Rgb(1, 2, 255))
+        );
+        csi(&mut state, &[49, 95], 'm');
+        assert_eq!(
+            (state.pen.fg, state.pen.bg),
+            (Color::Indexed(13), Color::Default)
+        );
+    }
+
+    #[test]
+    fn sgr_sets_colours_with_colons() {
+        let mut state = State::new(3, 2, 100);
+        sgr_with(&mut state, &[&[38, 5, 100], &[48, 2, 0, 10, 20, 30]]);
+        assert_eq!(state.pen.fg, Color::Indexed(100));
+        assert_eq!(state.pen.bg, Color::Rgb(10, 20, 30));
+        // The colour space id may be left out, and the next parameter is not
+        // taken as part of the colour.
+        sgr_with(&mut state, &[&[38, 2, 1, 2, 3], &[1]]);
+        assert_eq!(state.pen.fg, Color::Rgb(1, 2, 3));
+        assert!(state.pen.attrs.bold);
+        sgr_with(&mut state, &[&[4, 3]]);
+        assert!(state.pen.attrs.underline);
+        sgr_with(&mut state, &[&[4, 0]]);
+        assert!(!state.pen.attrs.underline);
+    }
+
+    #[test]
+    fn sgr_sets_and_clears_attributes() {
+        let mut state = State::new(3, 2, 100);
+        csi(&mut state, &[1, 2, 3, 4, 5, 7, 8, 9], 'm');
+        let all = Attributes {
+            bold: true,
+            dim: true,
+            italic: true,
+            underline: true,
+            blink: true,
+            inverse: true,
+            hidden: true,
+            strikethrough: true,
+        };
+        assert_eq!(state.pen.attrs, all);
+        csi(&mut state, &[22, 23], 'm');
+        assert!(!state.pen.attrs.bold && !state.pen.attrs.dim && !state.pen.attrs.italic);
+        csi(&mut state, &[24, 25, 27], 'm');
+        assert!(!state.pen.attrs.underline && !state.pen.attrs.blink && !state.pen.attrs.inverse);
+        assert!(state.pen.attrs.hidden && state.pen.attrs.strikethrough);
+        csi(&mut state, &[31, 1], 'm');
+        csi(&mut state, &[], 'm');
+        assert_eq!(state.pen, Cell::default());
+    }
+
+    #[test]
+    fn erase_cancels_pending_wrap() {
+        let mut state = State::new(3, 2, 100);
+        feed(&mut state, "abc");
+        assert!(state.wrap_pending);
+        csi(&mut state, &[], 'K');
+        feed(&mut state, "d");
+        assert_eq!(text(&state), vec!["abd", "   "]);
+    }
+}